@@ -37,12 +37,12 @@ impl<T> List<T> where T: Clone {
         List { head: self.head.clone() }
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.head.is_none()
+    }
+
     pub fn len(&self) -> usize {
-        if self.head.is_some() {
-            1 + self.tail().len()
-        } else {
-            0
-        }
+        self.iter().count()
     }
 
     pub fn tail(&self) -> List<T> {
@@ -54,16 +54,15 @@ impl<T> List<T> where T: Clone {
     }
 
     pub fn tail_tip(&self) -> Option<&T> {
-        let mut i = self.iter();
-        let mut val = i.next();
-        while let Some(v) = i.next() {
+        let mut val = None;
+        for v in self.iter() {
             val = Some(v);
         }
         val
     }
 
     pub fn iter(&self) -> Iter<'_, T> {
-        Iter { next: self.head.as_ref().map(|node| &**node) }
+        Iter { next: self.head.as_deref() }
     }
 
     pub fn reverse(list: Self) -> Self {
@@ -79,6 +78,12 @@ impl<T> List<T> where T: Clone {
     }
 }
 
+impl<T> Default for List<T> where T: Clone {
+    fn default() -> Self {
+        List::new()
+    }
+}
+
 impl<T> Drop for List<T> {
     fn drop(&mut self) {
         let mut head = self.head.take();
@@ -101,7 +106,7 @@ impl<'a, T> Iterator for Iter<'a, T> {
 
     fn next(&mut self) -> Option<Self::Item> {
         self.next.map(|node| {
-            self.next = node.next.as_ref().map(|node| &**node);
+            self.next = node.next.as_deref();
             &node.elm
         })
     }
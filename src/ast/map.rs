@@ -0,0 +1,313 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+
+const BITS: u32 = 5;
+const MASK: u64 = (1 << BITS) - 1;
+
+// A persistent hash map. Keys are found by their hash, 5 bits at a time,
+// through a tree of nodes that only hold the children in use. Adding or
+// removing a key copies the path to it, the rest is shared
+#[derive(Debug, Clone)]
+pub struct Map<K, V> {
+    root: Option<Rc<Node<K, V>>>,
+    len: usize,
+}
+
+#[derive(Debug)]
+enum Node<K, V> {
+    // Which 5 bit values have a child, and those children in order
+    Branch(u32, Vec<Rc<Node<K, V>>>),
+    // Every entry with this exact hash
+    Leaf(u64, Vec<(K, V)>),
+}
+
+fn hash_of<K: Hash>(key: &K) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn bit(hash: u64, shift: u32) -> u32 {
+    1 << ((hash >> shift) & MASK)
+}
+
+// Where the child for bit is in a branch
+fn index(bitmap: u32, bit: u32) -> usize {
+    (bitmap & (bit - 1)).count_ones() as usize
+}
+
+impl<K, V> Map<K, V> where K: Hash + Eq + Clone, V: Clone {
+    pub fn new() -> Self {
+        Map { root: None, len: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        let hash = hash_of(key);
+        let mut node = self.root.as_ref()?;
+        let mut shift = 0;
+        loop {
+            match &**node {
+                Node::Branch(bitmap, children) => {
+                    let b = bit(hash, shift);
+                    if bitmap & b == 0 {
+                        return None
+                    }
+                    node = &children[index(*bitmap, b)];
+                    shift += BITS;
+                },
+                Node::Leaf(h, entries) if *h == hash => {
+                    return entries.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+                },
+                Node::Leaf(_, _) => return None,
+            }
+        }
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn insert(&self, key: K, val: V) -> Self {
+        let hash = hash_of(&key);
+        let (root, added) = match &self.root {
+            Some(root) => insert_in(root, 0, hash, key, val),
+            None => (Rc::new(Node::Leaf(hash, vec![(key, val)])), true),
+        };
+        Map { root: Some(root), len: self.len + added as usize }
+    }
+
+    pub fn remove(&self, key: &K) -> Self {
+        let removed = self.root.as_ref().and_then(|root| remove_from(root, 0, hash_of(key), key));
+        match removed {
+            Some(root) => Map { root, len: self.len - 1 },
+            None => self.clone(),
+        }
+    }
+
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter {
+            nodes: self.root.iter().map(|n| &**n).collect(),
+            entries: [].iter(),
+        }
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item=&K> {
+        self.iter().map(|(k, _)| k)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item=&V> {
+        self.iter().map(|(_, v)| v)
+    }
+}
+
+// Returns the new node, and if the key wasn't there before
+fn insert_in<K, V>(node: &Rc<Node<K, V>>, shift: u32, hash: u64, key: K, val: V) -> (Rc<Node<K, V>>, bool)
+    where K: Eq + Clone, V: Clone {
+    match &**node {
+        Node::Branch(bitmap, children) => {
+            let b = bit(hash, shift);
+            let i = index(*bitmap, b);
+            let mut children = children.clone();
+            if bitmap & b == 0 {
+                children.insert(i, Rc::new(Node::Leaf(hash, vec![(key, val)])));
+                (Rc::new(Node::Branch(bitmap | b, children)), true)
+            } else {
+                let (child, added) = insert_in(&children[i], shift + BITS, hash, key, val);
+                children[i] = child;
+                (Rc::new(Node::Branch(*bitmap, children)), added)
+            }
+        },
+        Node::Leaf(h, entries) if *h == hash => {
+            let mut entries = entries.clone();
+            let added = match entries.iter().position(|(k, _)| *k == key) {
+                Some(i) => {
+                    entries[i].1 = val;
+                    false
+                },
+                None => {
+                    entries.push((key, val));
+                    true
+                },
+            };
+            (Rc::new(Node::Leaf(hash, entries)), added)
+        },
+        Node::Leaf(h, _) => {
+            // The hashes differ, so they split apart somewhere further down
+            let branch = Rc::new(Node::Branch(bit(*h, shift), vec![node.clone()]));
+            insert_in(&branch, shift, hash, key, val)
+        },
+    }
+}
+
+// None if the key isn't there, otherwise the new node, if there is anything left in it
+fn remove_from<K, V>(node: &Rc<Node<K, V>>, shift: u32, hash: u64, key: &K) -> Option<Option<Rc<Node<K, V>>>>
+    where K: Eq + Clone, V: Clone {
+    match &**node {
+        Node::Branch(bitmap, children) => {
+            let b = bit(hash, shift);
+            if bitmap & b == 0 {
+                return None
+            }
+            let i = index(*bitmap, b);
+            let child = remove_from(&children[i], shift + BITS, hash, key)?;
+            let mut children = children.clone();
+            match child {
+                Some(child) => {
+                    children[i] = child;
+                    Some(Some(Rc::new(Node::Branch(*bitmap, children))))
+                },
+                None if children.len() == 1 => Some(None),
+                None => {
+                    children.remove(i);
+                    Some(Some(Rc::new(Node::Branch(bitmap & !b, children))))
+                },
+            }
+        },
+        Node::Leaf(h, entries) if *h == hash => {
+            let i = entries.iter().position(|(k, _)| k == key)?;
+            if entries.len() == 1 {
+                return Some(None)
+            }
+            let mut entries = entries.clone();
+            entries.remove(i);
+            Some(Some(Rc::new(Node::Leaf(hash, entries))))
+        },
+        Node::Leaf(_, _) => None,
+    }
+}
+
+impl<K, V> Default for Map<K, V> where K: Hash + Eq + Clone, V: Clone {
+    fn default() -> Self {
+        Map::new()
+    }
+}
+
+// Maps with the same entries are equal, however they were built
+impl<K, V> PartialEq for Map<K, V> where K: Hash + Eq + Clone, V: Clone + PartialEq {
+    fn eq(&self, other: &Self) -> bool {
+        self.len == other.len && self.iter().all(|(k, v)| other.get(k) == Some(v))
+    }
+}
+
+impl<K, V> Eq for Map<K, V> where K: Hash + Eq + Clone, V: Clone + Eq {}
+
+// Entries are combined so that the order they are stored in doesn't matter
+impl<K, V> Hash for Map<K, V> where K: Hash + Eq + Clone, V: Clone + Hash {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let entries = self.iter().fold(0u64, |acc, entry| acc.wrapping_add(hash_of(&entry)));
+        self.len.hash(state);
+        entries.hash(state);
+    }
+}
+
+impl<K, V> std::iter::FromIterator<(K, V)> for Map<K, V> where K: Hash + Eq + Clone, V: Clone {
+    fn from_iter<I: IntoIterator<Item=(K, V)>>(iter: I) -> Self {
+        let mut m = Map::new();
+        for (k, v) in iter {
+            m = m.insert(k, v);
+        }
+        m
+    }
+}
+
+pub struct Iter<'a, K, V> {
+    nodes: Vec<&'a Node<K, V>>,
+    entries: std::slice::Iter<'a, (K, V)>,
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((k, v)) = self.entries.next() {
+                return Some((k, v))
+            }
+            match self.nodes.pop()? {
+                Node::Branch(_, children) => self.nodes.extend(children.iter().rev().map(|n| &**n)),
+                Node::Leaf(_, entries) => self.entries = entries.iter(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_insert_get() {
+        let m: Map<usize, usize> = (0..20000).map(|i| (i, i * 2)).collect();
+        assert_eq!(20000, m.len());
+        for i in 0..20000 {
+            assert_eq!(Some(&(i * 2)), m.get(&i));
+        }
+        assert_eq!(None, m.get(&20000));
+        assert_eq!(20000, m.iter().count());
+
+        let m2 = m.insert(5, 0);
+        assert_eq!(20000, m2.len());
+        assert_eq!(Some(&0), m2.get(&5));
+        assert_eq!(Some(&10), m.get(&5));
+    }
+
+    #[test]
+    fn test_remove() {
+        let m: Map<usize, usize> = (0..1000).map(|i| (i, i)).collect();
+        let mut m2 = m.clone();
+        for i in (0..1000).filter(|i| i % 3 == 0) {
+            m2 = m2.remove(&i);
+        }
+        assert_eq!(666, m2.len());
+        assert_eq!(None, m2.get(&3));
+        assert_eq!(Some(&4), m2.get(&4));
+        assert_eq!(Some(&3), m.get(&3));
+        assert_eq!(666, m2.remove(&3).len());
+
+        let mut empty = m;
+        for i in 0..1000 {
+            empty = empty.remove(&i);
+        }
+        assert!(empty.is_empty());
+        assert_eq!(0, empty.iter().count());
+    }
+
+    // Every key has the same hash, so they all end up in one leaf
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct Collides(usize);
+
+    impl Hash for Collides {
+        fn hash<H: Hasher>(&self, state: &mut H) {
+            0.hash(state)
+        }
+    }
+
+    #[test]
+    fn test_collisions() {
+        let m: Map<Collides, usize> = (0..10).map(|i| (Collides(i), i)).collect();
+        assert_eq!(10, m.len());
+        assert_eq!(Some(&7), m.get(&Collides(7)));
+        let m = m.remove(&Collides(7));
+        assert_eq!(None, m.get(&Collides(7)));
+        assert_eq!(Some(&8), m.get(&Collides(8)));
+        assert_eq!(9, m.len());
+    }
+
+    #[test]
+    fn test_equality() {
+        let a: Map<usize, usize> = (0..100).map(|i| (i, i)).collect();
+        let b: Map<usize, usize> = (0..100).rev().map(|i| (i, i)).collect();
+        assert_eq!(a, b);
+        assert_eq!(hash_of(&a), hash_of(&b));
+        assert!(a != b.insert(1, 2));
+    }
+}
@@ -1,6 +1,14 @@
 mod list;
+mod map;
+mod set;
+mod vector;
+
+use std::fmt;
 
 pub use list::List;
+pub use map::Map;
+pub use set::Set;
+pub use vector::Vector;
 
 pub type ASTList = list::List<ASTAtom>;
 pub type ASTAtom = (Atom, usize);
@@ -8,17 +16,71 @@ pub type ASTAtom = (Atom, usize);
 #[derive(Debug, Clone)]
 pub enum Atom {
     AIdentifier(String),
+    AKeyword(String), // Without the :
     AString(String),
-    // AFloat(f64),
+    AFloat(f64),
     AInteger(isize),
     ATrue,
     AFalse,
-    AList(list::List<Atom>),
-    // AVector(),
-    // AMap(),
-    // AChar(char),
+    AList(list::List<Atom>, usize), // And the line it was read from, 0 when it was made while compiling
+    AVector(list::List<Atom>), // Stored last element first, like lists
+    AMap(list::List<Atom>), // Keys and values one after the other
+    ASet(list::List<Atom>),
+    AChar(char),
 }
 
+// Characters that are written by name, like #\space
+pub const CHAR_NAMES: [(&str, char); 3] = [("space", ' '), ("newline", '\n'), ("tab", '\t')];
+
+// Writes c the way the reader reads it
+pub fn write_char(f: &mut fmt::Formatter, c: char) -> fmt::Result {
+    if let Some((name, _)) = CHAR_NAMES.iter().find(|(_, named)| *named == c) {
+        write!(f, "#\\{}", name)
+    } else if c.is_control() {
+        write!(f, "#\\x{:x}", c as u32)
+    } else {
+        write!(f, "#\\{}", c)
+    }
+}
 
+// Atoms nested deeper than this print as ..., rather then overflowing the stack
+const MAX_PRINT_DEPTH: usize = 256;
 
+// Prints atoms back out as source code
+impl fmt::Display for Atom {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_atom(f, self, 0)
+    }
+}
 
+fn write_atom(f: &mut fmt::Formatter, a: &Atom, depth: usize) -> fmt::Result {
+    match a {
+        Atom::AIdentifier(s) => write!(f, "{}", s),
+        Atom::AKeyword(s) => write!(f, ":{}", s),
+        Atom::AChar(c) => write_char(f, *c),
+        Atom::AString(s) => write!(f, "{:?}", s),
+        Atom::AFloat(v) => write!(f, "{:?}", v),
+        Atom::AInteger(v) => write!(f, "{}", v),
+        Atom::ATrue => write!(f, "true"),
+        Atom::AFalse => write!(f, "false"),
+        Atom::AList(l, _) => write_seq(f, "(", l, ")", depth),
+        Atom::AVector(l) => write_seq(f, "[", l, "]", depth),
+        Atom::AMap(l) => write_seq(f, "{", l, "}", depth),
+        Atom::ASet(l) => write_seq(f, "#{", l, "}", depth),
+    }
+}
+
+// Lists are stored last element first
+fn write_seq(f: &mut fmt::Formatter, open: &str, l: &List<Atom>, close: &str, depth: usize) -> fmt::Result {
+    if depth >= MAX_PRINT_DEPTH {
+        return write!(f, "{}...{}", open, close);
+    }
+    write!(f, "{}", open)?;
+    for (i, a) in List::reverse(l.copy()).iter().enumerate() {
+        if i > 0 {
+            write!(f, " ")?;
+        }
+        write_atom(f, a, depth + 1)?;
+    }
+    write!(f, "{}", close)
+}
@@ -0,0 +1,106 @@
+use std::hash::{Hash, Hasher};
+
+use super::map::Map;
+
+// A persistent hash set, a map where only the keys matter
+#[derive(Debug, Clone)]
+pub struct Set<T> {
+    map: Map<T, ()>,
+}
+
+impl<T> Set<T> where T: Hash + Eq + Clone {
+    pub fn new() -> Self {
+        Set { map: Map::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn contains(&self, elem: &T) -> bool {
+        self.map.contains_key(elem)
+    }
+
+    pub fn insert(&self, elem: T) -> Self {
+        Set { map: self.map.insert(elem, ()) }
+    }
+
+    pub fn remove(&self, elem: &T) -> Self {
+        Set { map: self.map.remove(elem) }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=&T> {
+        self.map.keys()
+    }
+
+    pub fn union(&self, other: &Self) -> Self {
+        // Adds the smaller one to the bigger one
+        let (big, small) = if self.len() >= other.len() { (self, other) } else { (other, self) };
+        small.iter().fold(big.clone(), |s, elem| s.insert(elem.clone()))
+    }
+
+    pub fn intersection(&self, other: &Self) -> Self {
+        self.iter().filter(|elem| !other.contains(elem)).fold(self.clone(), |s, elem| s.remove(elem))
+    }
+
+    pub fn difference(&self, other: &Self) -> Self {
+        other.iter().fold(self.clone(), |s, elem| s.remove(elem))
+    }
+
+    pub fn is_subset(&self, other: &Self) -> bool {
+        self.len() <= other.len() && self.iter().all(|elem| other.contains(elem))
+    }
+}
+
+impl<T> Default for Set<T> where T: Hash + Eq + Clone {
+    fn default() -> Self {
+        Set::new()
+    }
+}
+
+impl<T> PartialEq for Set<T> where T: Hash + Eq + Clone {
+    fn eq(&self, other: &Self) -> bool {
+        self.map == other.map
+    }
+}
+
+impl<T> Eq for Set<T> where T: Hash + Eq + Clone {}
+
+impl<T> Hash for Set<T> where T: Hash + Eq + Clone {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.map.hash(state)
+    }
+}
+
+impl<T> std::iter::FromIterator<T> for Set<T> where T: Hash + Eq + Clone {
+    fn from_iter<I: IntoIterator<Item=T>>(iter: I) -> Self {
+        Set { map: iter.into_iter().map(|elem| (elem, ())).collect() }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn set(elems: &[usize]) -> Set<usize> {
+        elems.iter().copied().collect()
+    }
+
+    #[test]
+    fn test_algebra() {
+        let a = set(&[1, 2, 3, 4]);
+        let b = set(&[3, 4, 5]);
+        assert_eq!(set(&[1, 2, 3, 4, 5]), a.union(&b));
+        assert_eq!(set(&[3, 4]), a.intersection(&b));
+        assert_eq!(set(&[1, 2]), a.difference(&b));
+        assert_eq!(set(&[5]), b.difference(&a));
+        assert!(set(&[3, 4]).is_subset(&a));
+        assert!(!b.is_subset(&a));
+        assert!(set(&[]).is_subset(&set(&[])));
+        assert_eq!(4, a.len());
+    }
+}
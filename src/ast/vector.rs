@@ -0,0 +1,269 @@
+use std::rc::Rc;
+
+const BITS: usize = 5;
+const WIDTH: usize = 1 << BITS;
+const MASK: usize = WIDTH - 1;
+
+// A persistent vector. Elements live in a tree 32 wide, so reading or
+// changing one only touches a path of log32(n) nodes, and every other
+// node is shared with the vector it was made from. The last (up to) 32
+// elements are kept out of the tree, which makes pushing cheap.
+// A vector can also be a window onto part of another, which is how
+// subvec shares everything with the vector it was taken from
+#[derive(Debug, Clone)]
+pub struct Vector<T> {
+    tree: Rc<Tree<T>>,
+    start: usize,
+    end: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Tree<T> {
+    len: usize,
+    shift: usize,
+    root: Rc<Node<T>>,
+    tail: Rc<Vec<T>>,
+}
+
+#[derive(Debug)]
+enum Node<T> {
+    Branch(Vec<Rc<Node<T>>>),
+    Leaf(Vec<T>),
+}
+
+impl<T> Vector<T> where T: Clone {
+    pub fn new() -> Self {
+        Vector {
+            tree: Rc::new(Tree {
+                len: 0,
+                shift: BITS,
+                root: Rc::new(Node::Branch(Vec::new())),
+                tail: Rc::new(Vec::new()),
+            }),
+            start: 0,
+            end: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, i: usize) -> Option<&T> {
+        if i < self.len() {
+            Some(self.tree.get(self.start + i))
+        } else {
+            None
+        }
+    }
+
+    // A copy with the element at i replaced, i can't be past the end
+    pub fn set(&self, i: usize, elem: T) -> Option<Self> {
+        if i > self.len() {
+            return None
+        }
+        if i == self.len() {
+            return Some(self.push(elem))
+        }
+        Some(Vector { tree: Rc::new(self.tree.set(self.start + i, elem)), ..self.clone() })
+    }
+
+    pub fn push(&self, elem: T) -> Self {
+        // Past the end of a window, the underlying elements are just replaced
+        let tree = if self.end < self.tree.len {
+            self.tree.set(self.end, elem)
+        } else {
+            self.tree.push(elem)
+        };
+        Vector { tree: Rc::new(tree), start: self.start, end: self.end + 1 }
+    }
+
+    // The elements from start up to end, sharing this vector's storage
+    pub fn slice(&self, start: usize, end: usize) -> Option<Self> {
+        if start > end || end > self.len() {
+            return None
+        }
+        Some(Vector {
+            tree: self.tree.clone(),
+            start: self.start + start,
+            end: self.start + end,
+        })
+    }
+
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter { vector: self, i: 0 }
+    }
+}
+
+impl<T> Default for Vector<T> where T: Clone {
+    fn default() -> Self {
+        Vector::new()
+    }
+}
+
+impl<T> std::iter::FromIterator<T> for Vector<T> where T: Clone {
+    fn from_iter<I: IntoIterator<Item=T>>(iter: I) -> Self {
+        let mut v = Vector::new();
+        for elem in iter {
+            v = v.push(elem);
+        }
+        v
+    }
+}
+
+impl<T> Tree<T> where T: Clone {
+    // Where the elements kept in tail begin
+    fn tail_start(&self) -> usize {
+        if self.len < WIDTH {
+            0
+        } else {
+            ((self.len - 1) >> BITS) << BITS
+        }
+    }
+
+    fn get(&self, i: usize) -> &T {
+        if i >= self.tail_start() {
+            return &self.tail[i - self.tail_start()]
+        }
+        let mut node = &self.root;
+        let mut level = self.shift;
+        loop {
+            match &**node {
+                Node::Branch(children) => node = &children[(i >> level) & MASK],
+                Node::Leaf(elems) => return &elems[i & MASK],
+            }
+            level -= BITS;
+        }
+    }
+
+    fn set(&self, i: usize, elem: T) -> Self {
+        if i >= self.tail_start() {
+            let mut tail = (*self.tail).clone();
+            tail[i - self.tail_start()] = elem;
+            return Tree { tail: Rc::new(tail), ..self.clone() }
+        }
+        Tree { root: Tree::set_in(&self.root, self.shift, i, elem), ..self.clone() }
+    }
+
+    fn set_in(node: &Rc<Node<T>>, level: usize, i: usize, elem: T) -> Rc<Node<T>> {
+        Rc::new(match &**node {
+            Node::Branch(children) => {
+                let mut children = children.clone();
+                let sub = (i >> level) & MASK;
+                children[sub] = Tree::set_in(&children[sub], level - BITS, i, elem);
+                Node::Branch(children)
+            },
+            Node::Leaf(elems) => {
+                let mut elems = elems.clone();
+                elems[i & MASK] = elem;
+                Node::Leaf(elems)
+            },
+        })
+    }
+
+    fn push(&self, elem: T) -> Self {
+        if self.len - self.tail_start() < WIDTH {
+            let mut tail = (*self.tail).clone();
+            tail.push(elem);
+            return Tree { len: self.len + 1, tail: Rc::new(tail), ..self.clone() }
+        }
+        // The tail is full, so it moves into the tree
+        let leaf = Rc::new(Node::Leaf((*self.tail).clone()));
+        let (root, shift) = if (self.len >> BITS) > (1 << self.shift) {
+            let path = Tree::path_to(self.shift, leaf);
+            (Rc::new(Node::Branch(vec![self.root.clone(), path])), self.shift + BITS)
+        } else {
+            (self.push_leaf(&self.root, self.shift, leaf), self.shift)
+        };
+        Tree { len: self.len + 1, shift, root, tail: Rc::new(vec![elem]) }
+    }
+
+    fn push_leaf(&self, node: &Rc<Node<T>>, level: usize, leaf: Rc<Node<T>>) -> Rc<Node<T>> {
+        let mut children = match &**node {
+            Node::Branch(children) => children.clone(),
+            Node::Leaf(_) => panic!("Vector leaf above the bottom level"),
+        };
+        let sub = ((self.len - 1) >> level) & MASK;
+        if level == BITS {
+            children.push(leaf);
+        } else if sub < children.len() {
+            children[sub] = self.push_leaf(&children[sub], level - BITS, leaf);
+        } else {
+            children.push(Tree::path_to(level - BITS, leaf));
+        }
+        Rc::new(Node::Branch(children))
+    }
+
+    // A chain of single child branches from level down to leaf
+    fn path_to(level: usize, leaf: Rc<Node<T>>) -> Rc<Node<T>> {
+        if level == 0 {
+            leaf
+        } else {
+            Rc::new(Node::Branch(vec![Tree::path_to(level - BITS, leaf)]))
+        }
+    }
+}
+
+pub struct Iter<'a, T> {
+    vector: &'a Vector<T>,
+    i: usize,
+}
+
+impl<'a, T> Iterator for Iter<'a, T> where T: Clone {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let v = self.vector.get(self.i);
+        self.i += 1;
+        v
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_push_get() {
+        // Enough to need a few levels of tree
+        let v: Vector<usize> = (0..40000).collect();
+        assert_eq!(40000, v.len());
+        for i in 0..40000 {
+            assert_eq!(Some(&i), v.get(i));
+        }
+        assert_eq!(None, v.get(40000));
+        assert!(v.iter().copied().eq(0..40000));
+    }
+
+    #[test]
+    fn test_persistence() {
+        let a: Vector<usize> = (0..1000).collect();
+        let b = a.set(500, 0).expect("in bounds");
+        let c = a.push(1000);
+        assert_eq!(Some(&500), a.get(500));
+        assert_eq!(Some(&0), b.get(500));
+        assert_eq!(1000, a.len());
+        assert_eq!(Some(&1000), c.get(1000));
+        assert_eq!(Some(&999), b.get(999));
+        assert!(a.set(1001, 0).is_none());
+    }
+
+    #[test]
+    fn test_slice() {
+        let a: Vector<usize> = (0..100).collect();
+        let s = a.slice(10, 20).expect("in bounds");
+        assert_eq!(10, s.len());
+        assert!(s.iter().copied().eq(10..20));
+        // Pushing onto a window doesn't change what it was taken from
+        let t = s.push(0);
+        assert_eq!(Some(&0), t.get(10));
+        assert_eq!(Some(&20), a.get(20));
+        assert!(a.slice(20, 10).is_none());
+        assert!(a.slice(0, 101).is_none());
+        assert!(s.slice(5, 10).expect("in bounds").iter().copied().eq(15..20));
+    }
+}
@@ -1,12 +1,19 @@
 use std::mem::transmute;
 
+use std::cell::RefCell;
 use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::ops;
+use std::rc::Rc;
 
-use crate::ast::List;
+use crate::ast::{self, List, Map, Set, Vector};
+use crate::chunk::Chunk;
 
 
-#[derive(Debug, Copy, Clone)]
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum Op {
     Negate,
     Add,
@@ -17,6 +24,34 @@ pub enum Op {
     Car,
     Cdr,
     Cons,
+    Splice,
+
+    Nth,
+    Assoc,
+    Conj,
+    Count,
+    Subvec,
+    Get,
+    Dissoc,
+    Keys,
+    Vals,
+    Contains,
+    Merge,
+    Union,
+    Intersection,
+    Difference,
+    IsSubset,
+
+    IsSymbol,
+    SymbolToString,
+    StringToSymbol,
+    IsKeyword,
+
+    CharToInteger,
+    IntegerToChar,
+    StringRef,
+    CharUpcase,
+    IsAlphabetic,
 
     Equal,
     LessEqual,
@@ -32,27 +67,140 @@ pub enum Op {
     Const2, // Uses next 2 bytes to identify constant number
     Const3, // Uses next 3 bytes to identify constant number
 
-    Load,
+    Load, // Uses next byte as the slot, then one as how many frames back it is
     Store,
     Set,
+    LoadWide, // Like Load, but with 2 bytes each for the slot and frame
+    SetWide,
+
+    DefGlobal, // Globals take their name from a symbol on top of the stack
+    GetGlobal,
+    SetGlobal,
 
     CreateFrame,
     DropFrame,
     DropFrameSaveReturn,
+    CloseUpvalues, // Uses next 2 bytes as how many frames back, closes what was captured from there up
 
-    Jump,
+    Jump, // Uses next 2 bytes as a signed offset from the end of the jump
     JumpTrue,
+    JumpWide, // Like Jump, but with a 4 byte offset
+    JumpTrueWide,
+
+    Call, // Uses next byte as the argument count
+    TailCall, // Like Call, but replaces the current function's frame
+    Closure, // Uses next byte as the capture count, then 3 bytes per capture, or 5 for a wide local
+    GetUpvalue,
+    SetUpvalue,
+
+    Try, // Uses next 2 bytes as the offset to the handler, which starts with the error in a new frame
+    Protect, // Like Try, but the handler gets nothing and should end with Rethrow
+    TryWide, // Try and Protect with a 4 byte offset
+    ProtectWide,
+    EndTry, // Removes the innermost Try or Protect handler
+    Rethrow,
+    Throw,
+    IsError,
+    ErrorMessage,
 
-    NoOp,
+    NoOp, // Must stay the last op, from_lit uses it as the upper bound
 }
 
 impl Op {
     pub fn from_lit(val: u8) -> Op {
+        assert!(val <= Op::NoOp as u8, "{} is not an opcode", val);
         unsafe { transmute(val) }
     }
 
-    pub fn to_lit(&self) -> u8 {
-        unsafe { transmute(*self as u8) }
+    pub fn to_lit(self) -> u8 {
+        self as u8
+    }
+}
+
+pub struct Function {
+    pub name: Option<String>,
+    pub arity: usize, // Required parameters
+    pub optional: usize,
+    pub rest: bool,
+    // Where to start, by how many optional arguments were given. The code
+    // between them works out the defaults of the ones that were left out
+    pub entries: Vec<usize>,
+    pub chunk: Chunk,
+}
+
+impl fmt::Debug for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "<function {}>", name),
+            None => write!(f, "<function>"),
+        }
+    }
+}
+
+thread_local! {
+    static SYMBOLS: RefCell<HashMap<String, Rc<str>>> = RefCell::new(HashMap::new());
+}
+
+// An interned name. Every symbol with the same name shares one allocation,
+// so comparing them is a pointer comparison
+#[derive(Clone)]
+pub struct Symbol(Rc<str>);
+
+impl Symbol {
+    pub fn intern(name: &str) -> Symbol {
+        SYMBOLS.with(|symbols| {
+            let mut symbols = symbols.borrow_mut();
+            if let Some(s) = symbols.get(name) {
+                return Symbol(s.clone())
+            }
+            let s: Rc<str> = Rc::from(name);
+            symbols.insert(String::from(name), s.clone());
+            Symbol(s)
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Symbol) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Symbol {}
+
+impl Hash for Symbol {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        Rc::as_ptr(&self.0).hash(state)
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "'{}", self.as_str())
+    }
+}
+
+// A captured variable. It points into the stack while its frame is alive,
+// and holds the value itself once the frame has been dropped
+#[derive(Debug)]
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+pub struct Closure {
+    pub function: Rc<Function>,
+    pub upvalues: Vec<Rc<RefCell<Upvalue>>>,
+}
+
+impl fmt::Debug for Closure {
+    // Upvalues are left out, a closure can capture itself
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.function.fmt(f)
     }
 }
 
@@ -61,8 +209,16 @@ pub enum Value {
     VFloat(f64),
     VInt(isize),
     VString(String),
+    VChar(char),
     VBool(bool),
+    VSymbol(Symbol),
+    VKeyword(Symbol), // Named like a symbol, but evaluates to itself
     VList(List<Value>),
+    VVector(Vector<Value>),
+    VMap(Map<Value, Value>),
+    VSet(Set<Value>),
+    VFunction(Rc<Closure>),
+    VError(String), // A caught runtime error, with its message
     VErr,
 }
 
@@ -72,8 +228,16 @@ impl Value {
             VFloat(_) => String::from("Float"),
             VInt(_) => String::from("Int"),
             VString(_) => String::from("String"),
+            VChar(_) => String::from("Char"),
             VBool(_) => String::from("Bool"),
+            VSymbol(_) => String::from("Symbol"),
+            VKeyword(_) => String::from("Keyword"),
             VList(_) => String::from("List"),
+            VVector(_) => String::from("Vector"),
+            VMap(_) => String::from("Map"),
+            VSet(_) => String::from("Set"),
+            VFunction(_) => String::from("Function"),
+            VError(_) => String::from("Error"),
             VErr => String::from("Error"),
         }
     }
@@ -81,22 +245,74 @@ impl Value {
         match self {
             VFloat(f) => !f.is_nan() && *f != 0.0,
             VInt(i) => *i != 0,
-            VString(s) => s.len() > 0,
+            VString(s) => !s.is_empty(),
+            VChar(_) => true,
             VBool(b) => *b,
-            VList(l) => l.len() == 0,
+            VSymbol(_) => true,
+            VKeyword(_) => true,
+            VList(l) => l.is_empty(),
+            VVector(v) => !v.is_empty(),
+            VMap(m) => !m.is_empty(),
+            VSet(s) => !s.is_empty(),
+            VFunction(_) => true,
+            VError(_) => true,
             VErr => panic!("VErr should not be used"),
         }
     }
 }
 
 use Value::*;
+
+// Values nested deeper than this print as ..., rather then overflowing the stack
+const MAX_PRINT_DEPTH: usize = 256;
+
+// Prints values the way they would be written in source
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_value(f, self, 0)
+    }
+}
+
+fn write_value(f: &mut fmt::Formatter, v: &Value, depth: usize) -> fmt::Result {
+    match v {
+        VFloat(v) => write!(f, "{:?}", v),
+        VInt(v) => write!(f, "{}", v),
+        VString(v) => write!(f, "{:?}", v),
+        VChar(c) => ast::write_char(f, *c),
+        VBool(v) => write!(f, "{}", v),
+        VSymbol(v) => write!(f, "{}", v.as_str()),
+        VKeyword(v) => write!(f, ":{}", v.as_str()),
+        VList(l) => write_seq(f, "(", l.iter(), ")", depth),
+        VVector(v) => write_seq(f, "[", v.iter(), "]", depth),
+        VMap(m) => write_seq(f, "{", m.iter().flat_map(|(k, v)| [k, v]), "}", depth),
+        VSet(s) => write_seq(f, "#{", s.iter(), "}", depth),
+        VFunction(c) => write!(f, "{:?}", c),
+        VError(msg) => write!(f, "<error {:?}>", msg),
+        VErr => write!(f, "<error>"),
+    }
+}
+
+fn write_seq<'a>(f: &mut fmt::Formatter, open: &str, vals: impl Iterator<Item=&'a Value>, close: &str, depth: usize) -> fmt::Result {
+    if depth >= MAX_PRINT_DEPTH {
+        return write!(f, "{}...{}", open, close);
+    }
+    write!(f, "{}", open)?;
+    for (i, v) in vals.enumerate() {
+        if i > 0 {
+            write!(f, " ")?;
+        }
+        write_value(f, v, depth + 1)?;
+    }
+    write!(f, "{}", close)
+}
+
 impl ops::Add<Value> for Value {
     type Output = Value;
     fn add(self, rhs: Value) -> Value {
         match self {
             VInt(i) => {
                 match rhs {
-                    VInt(i2) => VInt(i + i2),
+                    VInt(i2) => i.checked_add(i2).map_or(VErr, VInt),
                     VFloat(f2) => VFloat(i as f64 + f2),
                     _ => VErr,
                 }
@@ -121,14 +337,18 @@ impl ops::Add<Value> for Value {
 impl ops::Sub<Value> for Value {
     type Output = Value;
     fn sub(self, rhs: Value) -> Value {
-        self + (-rhs)
+        match (self, rhs) {
+            // Negating rhs first would overflow on the smallest integer
+            (VInt(i), VInt(i2)) => i.checked_sub(i2).map_or(VErr, VInt),
+            (lhs, rhs) => lhs + (-rhs),
+        }
     }
 }
 impl ops::Neg for Value {
     type Output = Value;
     fn neg(self) -> Value {
         match self {
-            VInt(i) => VInt(-i),
+            VInt(i) => i.checked_neg().map_or(VErr, VInt),
             VFloat(f) => VFloat(-f),
             _ => VErr,
         }
@@ -140,7 +360,7 @@ impl ops::Mul<Value> for Value {
         match self {
             VInt(i) => {
                 match rhs {
-                    VInt(i2) => VInt(i * i2),
+                    VInt(i2) => i.checked_mul(i2).map_or(VErr, VInt),
                     VFloat(f2) => VFloat(i as f64 * f2),
                     _ => VErr,
                 }
@@ -168,7 +388,10 @@ impl ops::Div<Value> for Value {
         match self {
             VInt(i) => {
                 match rhs {
-                    VInt(i2) => VInt(i / i2),
+                    VInt(i2) => match i.checked_div(i2) {
+                        Some(v) => VInt(v),
+                        None => VErr,
+                    },
                     VFloat(f2) => VFloat(i as f64 / f2),
                     _ => VErr,
                 }
@@ -196,13 +419,26 @@ impl PartialOrd for Value {
                 }
             },
             VBool(_) => None,
+            VSymbol(_) => None,
+            VKeyword(_) => None,
             VList(_) => None,
+            VVector(_) => None,
+            VMap(_) => None,
+            VSet(_) => None,
+            VFunction(_) => None,
+            VError(_) => None,
             VString(s) => {
                 match other {
                     VString(o) => s.partial_cmp(o),
                     _ => None,
                 }
             },
+            VChar(c) => {
+                match other {
+                    VChar(o) => c.partial_cmp(o),
+                    _ => None,
+                }
+            },
             VFloat(s) => {
                 match other {
                     VInt(o) => s.partial_cmp(&(*o as f64)),
@@ -218,8 +454,10 @@ impl PartialEq for Value {
     fn eq(&self, other: &Value) -> bool {
         match self {
             VInt(s) => {
-                if let VInt(o) = other {
-                    return o == s;
+                match other {
+                    VInt(o) => return o == s,
+                    VFloat(o) => return whole_float(*o) == Some(*s),
+                    _ => (),
                 }
             },
             VBool(s) => {
@@ -227,22 +465,106 @@ impl PartialEq for Value {
                     return o == s;
                 }
             },
+            VSymbol(s) => {
+                if let VSymbol(o) = other {
+                    return o == s;
+                }
+            },
+            VKeyword(s) => {
+                if let VKeyword(o) = other {
+                    return o == s;
+                }
+            },
             VList(s) => {
-                return false;
+                if let VList(o) = other {
+                    return s.len() == o.len() && s.iter().zip(o.iter()).all(|(a, b)| a == b);
+                }
+            },
+            VVector(s) => {
+                if let VVector(o) = other {
+                    return s.len() == o.len() && s.iter().zip(o.iter()).all(|(a, b)| a == b);
+                }
+            },
+            VMap(s) => {
+                if let VMap(o) = other {
+                    return s == o;
+                }
+            },
+            VSet(s) => {
+                if let VSet(o) = other {
+                    return s == o;
+                }
+            },
+            VFunction(s) => {
+                if let VFunction(o) = other {
+                    return Rc::ptr_eq(s, o);
+                }
             },
             VString(s) => {
                 if let VString(o) = other {
                     return o == s;
                 }
             },
+            VChar(c) => {
+                if let VChar(o) = other {
+                    return o == c;
+                }
+            },
             VFloat(s) => {
-                if let VFloat(o) = other {
+                match other {
+                    // NaN has to equal itself to be found as a map key
+                    VFloat(o) => return o == s || (o.is_nan() && s.is_nan()),
+                    VInt(o) => return whole_float(*s) == Some(*o),
+                    _ => (),
+                }
+            },
+            VError(s) => {
+                if let VError(o) = other {
                     return o == s;
                 }
             },
-            VErr => return false
+            VErr => return matches!(other, VErr)
         };
-        return false;
+        false
+    }
+}
+
+impl Eq for Value {}
+
+// The integer a float equals, when it is a whole number in range
+fn whole_float(f: f64) -> Option<isize> {
+    if f.fract() == 0.0 && f >= isize::MIN as f64 && f < isize::MAX as f64 {
+        Some(f as isize)
+    } else {
+        None
+    }
+}
+
+// Equal values have to hash the same, so whole floats hash like integers, and all NaNs alike
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        if let VFloat(f) = self {
+            if let Some(i) = whole_float(*f) {
+                return VInt(i).hash(state)
+            }
+        }
+        std::mem::discriminant(self).hash(state);
+        match self {
+            VFloat(f) if f.is_nan() => f64::NAN.to_bits().hash(state),
+            VFloat(f) => f.to_bits().hash(state),
+            VInt(i) => i.hash(state),
+            VString(s) | VError(s) => s.hash(state),
+            VBool(b) => b.hash(state),
+            VChar(c) => c.hash(state),
+            // By name rather than address, so maps come out in the same order every run
+            VSymbol(s) | VKeyword(s) => s.as_str().hash(state),
+            VList(l) => l.iter().for_each(|v| v.hash(state)),
+            VVector(v) => v.iter().for_each(|v| v.hash(state)),
+            VMap(m) => m.hash(state),
+            VSet(s) => s.hash(state),
+            VFunction(c) => Rc::as_ptr(c).hash(state),
+            VErr => (),
+        }
     }
 }
 
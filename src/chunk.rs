@@ -1,46 +1,95 @@
-use crate::bytecode::Op;
+use crate::bytecode::{Op, Symbol};
 use crate::bytecode::Value;
 
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+// Constants are referred to by index, 1 to 3 bytes long, most significant byte first
+const MAX_CONSTANTS: usize = 1 << 24;
+
 #[derive(Debug)]
 pub struct Chunk {
-    pub code: Vec<Op>,
-    lines: Vec<usize>,
+    pub code: Vec<u8>,
+    lines: Vec<(usize, usize)>, // Where in code each run of a line starts, and the line
     values: Vec<Value>,
+    indexes: HashMap<ConstKey, usize>, // Constants that can be shared by every use
+}
+
+// Identifies a constant that has already been added. Floats go by their bits,
+// so 0.0 and -0.0 are kept apart
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum ConstKey {
+    Int(isize),
+    Float(u64),
+    Str(String),
+    Bool(bool),
+    Symbol(Symbol),
+    Keyword(Symbol),
+    Char(char),
+}
+
+impl ConstKey {
+    fn from_value(val: &Value) -> Option<ConstKey> {
+        Some(match val {
+            Value::VInt(i) => ConstKey::Int(*i),
+            Value::VFloat(f) => ConstKey::Float(f.to_bits()),
+            Value::VString(s) => ConstKey::Str(s.clone()),
+            Value::VBool(b) => ConstKey::Bool(*b),
+            Value::VSymbol(s) => ConstKey::Symbol(s.clone()),
+            Value::VKeyword(s) => ConstKey::Keyword(s.clone()),
+            Value::VChar(c) => ConstKey::Char(*c),
+            _ => return None,
+        })
+    }
 }
 
 impl Chunk {
     pub fn disassemble(&self) {
-        disassemble_inner(&self, 0)
+        disassemble_inner(self, 0)
     }
 
-    pub fn add_constant(&mut self, val: Value, line: usize) -> usize {
-        let i = self.values.len();
-        self.values.push(val);
+    pub fn add_constant(&mut self, val: Value, line: usize) -> Result<usize, String> {
+        let i = self.constant_index(val)?;
         match i {
-            x if x < 2usize.pow(8) => {
+            x if x < 1 << 8 => {
                 self.add_op(Op::Const1, line);
-                self.add_op(Op::from_lit(x as u8), line);
+                self.add_lit(x as u8, line);
             },
-            x if x < 2usize.pow(16) => {
+            x if x < 1 << 16 => {
                 self.add_op(Op::Const2, line);
-                self.add_op(Op::from_lit(((x >> 8) | 0xFF) as u8), line);
-                self.add_op(Op::from_lit((x | 0xFF) as u8), line);
+                self.add_lit((x >> 8) as u8, line);
+                self.add_lit(x as u8, line);
             },
-            x if x < 2usize.pow(32) => {
+            x => {
                 self.add_op(Op::Const3, line);
-                self.add_op(Op::from_lit(((x >> 16) | 0xFF) as u8), line);
-                self.add_op(Op::from_lit(((x >> 8) | 0xFF) as u8), line);
-                self.add_op(Op::from_lit((x | 0xFF) as u8), line);
+                self.add_lit((x >> 16) as u8, line);
+                self.add_lit((x >> 8) as u8, line);
+                self.add_lit(x as u8, line);
             },
-            _ => panic!("Sorry, a program can't declare more then 4294967295 variables")
+        }
+        Ok(i)
+    }
 
+    // Reuses an equal constant when there is one
+    fn constant_index(&mut self, val: Value) -> Result<usize, String> {
+        let key = ConstKey::from_value(&val);
+        if let Some(i) = key.as_ref().and_then(|k| self.indexes.get(k)) {
+            return Ok(*i)
         }
-        i
+        let i = self.values.len();
+        if i >= MAX_CONSTANTS {
+            return Err(format!("Can't have more then {} constants in one function", MAX_CONSTANTS))
+        }
+        self.values.push(val);
+        if let Some(k) = key {
+            self.indexes.insert(k, i);
+        }
+        Ok(i)
     }
 
     // i is to be the opcode refering to the constant (eg. Const1)
     pub fn read_constant(&self, i: usize) -> Value {
-        match self.code[i] {
+        match Op::from_lit(self.code[i]) {
             Op::Const1 => self.read_constant_internal(i+1, 1),
             Op::Const2 => self.read_constant_internal(i+1, 2),
             Op::Const3 => self.read_constant_internal(i+1, 3),
@@ -51,37 +100,124 @@ impl Chunk {
         let mut index: usize = 0;
         for v in &self.code[loc..loc+length] {
             index *= 256;
-            index += v.to_lit() as usize;
+            index += *v as usize;
         }
         self.values[index].clone()
     }
 
     pub fn add_op(&mut self, op: Op, line: usize) -> usize {
-        self.adding_op_line(line);
-        self.code.push(op);
+        self.add_lit(op.to_lit(), line)
+    }
+
+    // Adds a raw data byte, such as an operand of the previous op
+    pub fn add_lit(&mut self, val: u8, line: usize) -> usize {
+        self.mark_line(line);
+        self.code.push(val);
         self.code.len() - 1
     }
 
-    pub fn replace_instruction(&mut self, i: usize, op: Op) {
-        if let Op::Const1 = self.code[i] {
-            self.code.remove(i+1);
-            println!("{}", i);
+    // Adds a Load or Set of a local, or the wide version if it doesn't fit in a byte
+    pub fn add_local(&mut self, op: Op, loc: usize, stack_back: usize, line: usize) -> Result<(), String> {
+        if loc <= u8::MAX as usize && stack_back <= u8::MAX as usize {
+            self.add_op(op, line);
+            self.add_lit(loc as u8, line);
+            self.add_lit(stack_back as u8, line);
+            return Ok(())
+        }
+        if loc > u16::MAX as usize || stack_back > u16::MAX as usize {
+            return Err(format!("Can't have more then {} locals or nested scopes", u16::MAX as usize + 1))
+        }
+        let wide = match op {
+            Op::Load => Op::LoadWide,
+            Op::Set => Op::SetWide,
+            _ => panic!("{:?} has no wide version", op),
+        };
+        self.add_op(wide, line);
+        for byte in (loc as u16).to_be_bytes().iter().chain((stack_back as u16).to_be_bytes().iter()) {
+            self.add_lit(*byte, line);
+        }
+        Ok(())
+    }
+
+    pub fn read_u16(&self, at: usize) -> u16 {
+        u16::from_be_bytes([self.code[at], self.code[at + 1]])
+    }
+
+    // Adds a jump op with an offset to fill in later, returns where the offset is.
+    // Wide jumps have 4 bytes for the offset rather then 2
+    pub fn add_jump(&mut self, op: Op, wide: bool, line: usize) -> usize {
+        let (op, size) = match (op, wide) {
+            (_, false) => (op, 2),
+            (Op::Jump, true) => (Op::JumpWide, 4),
+            (Op::JumpTrue, true) => (Op::JumpTrueWide, 4),
+            (Op::Try, true) => (Op::TryWide, 4),
+            (Op::Protect, true) => (Op::ProtectWide, 4),
+            _ => panic!("{:?} has no wide version", op),
+        };
+        self.add_op(op, line);
+        let at = self.code.len();
+        for _ in 0..size {
+            self.add_lit(0, line);
+        }
+        at
+    }
+
+    // Points the jump with its offset at `at` to the next op added
+    pub fn patch_jump(&mut self, at: usize) -> Result<(), String> {
+        self.patch_jump_to(at, self.code.len())
+    }
+
+    // Offsets are signed and counted from the end of the jump
+    pub fn patch_jump_to(&mut self, at: usize, target: usize) -> Result<(), String> {
+        let size = self.jump_size(at);
+        let offset = target as isize - (at + size) as isize;
+        let (bytes, most) = if size == 4 {
+            (i32::try_from(offset).map(|o| o.to_be_bytes().to_vec()), i32::MAX as isize)
+        } else {
+            (i16::try_from(offset).map(|o| o.to_be_bytes().to_vec()), i16::MAX as isize)
+        };
+        match bytes {
+            Ok(bytes) => {
+                self.code[at..at + size].copy_from_slice(&bytes);
+                Ok(())
+            },
+            Err(_) => Err(format!("Can't jump {} bytes, the most is {}", offset, most)),
+        }
+    }
+
+    pub fn read_jump(&self, at: usize) -> isize {
+        let c = &self.code;
+        if self.jump_size(at) == 4 {
+            i32::from_be_bytes([c[at], c[at + 1], c[at + 2], c[at + 3]]) as isize
+        } else {
+            i16::from_be_bytes([c[at], c[at + 1]]) as isize
+        }
+    }
+
+    // How many bytes the offset at `at` is, going by the op before it
+    pub fn jump_size(&self, at: usize) -> usize {
+        match Op::from_lit(self.code[at - 1]) {
+            Op::JumpWide | Op::JumpTrueWide | Op::TryWide | Op::ProtectWide => 4,
+            _ => 2,
         }
-        self.code[i] = op
     }
 
     pub fn get_line(&self, i: usize) -> usize {
-        for (index, s) in self.lines.iter().enumerate() {
-            if *s > i {
-                return index
-            }
+        match self.lines.partition_point(|(start, _)| *start <= i) {
+            0 => 0,
+            run => self.lines[run - 1].1,
         }
-        self.lines.len()
     }
 
-    fn adding_op_line(&mut self, line: usize) {
-        if line > self.lines.len() {
-            self.lines.push(self.code.len());
+    // Code added from here on is on line. Nested forms can go back to an earlier line
+    pub fn mark_line(&mut self, line: usize) {
+        if line == 0 {
+            return
+        }
+        match self.lines.last_mut() {
+            Some((_, l)) if *l == line => (),
+            Some((start, l)) if *start == self.code.len() => *l = line,
+            _ => self.lines.push((self.code.len(), line)),
         }
     }
 
@@ -98,21 +234,56 @@ fn disassemble_inner(c: &Chunk, i: usize) {
 }
 
 pub fn disassemble_instruction(c: &Chunk, i: usize) -> usize{
-    match c.code[i] {
+    let op = Op::from_lit(c.code[i]);
+    match op {
         Op::Equal | Op::Less | Op::Greater | Op::GreaterEqual | Op::LessEqual | Op::Discard => {
-            disassemble_with_data1(c.code[i], c.get_line(i), i, c.code[i+1].to_lit()); 2
+            disassemble_with_data1(op, c.get_line(i), i, c.code[i+1]); 2
+        },
+        Op::Const1 => { disassemble_const(c, op, i); 2 },
+        Op::Const2 => { disassemble_const(c, op, i); 3 },
+        Op::Const3 => { disassemble_const(c, op, i); 4 },
+        Op::Jump | Op::JumpTrue | Op::Try | Op::Protect |
+        Op::JumpWide | Op::JumpTrueWide | Op::TryWide | Op::ProtectWide => {
+            let size = 1 + c.jump_size(i + 1);
+            let target = (i + size) as isize + c.read_jump(i + 1);
+            disassemble_op(op, c.get_line(i), i);
+            println!(" -> {:05X}", target);
+            size
+        },
+        Op::Call | Op::TailCall | Op::GetUpvalue | Op::SetUpvalue => {
+            disassemble_with_data1(op, c.get_line(i), i, c.code[i+1]); 2
         },
-        Op::Const1 => { disassemble_const(c, c.code[i], i); 2 },
-        Op::Const2 => { disassemble_const(c, c.code[i], i); 3 },
-        Op::Const3 => { disassemble_const(c, c.code[i], i); 4 },
-        Op::Jump | Op::JumpTrue => {
-            disassemble_with_data1(c.code[i], c.get_line(i), i, c.code[i+1].to_lit()); 2
+        Op::Load | Op::Set => {
+            disassemble_with_data2(op, c.get_line(i), i, c.code[i+1] as usize, c.code[i+2] as usize); 3
         },
-        Op::Load => {
-            disassemble_with_data2(c.code[i], c.get_line(i), i, c.code[i+1].to_lit(), c.code[i+2].to_lit()); 3
+        Op::LoadWide | Op::SetWide => {
+            disassemble_with_data2(op, c.get_line(i), i, c.read_u16(i+1) as usize, c.read_u16(i+3) as usize); 5
+        },
+        Op::CloseUpvalues => {
+            disassemble_op(op, c.get_line(i), i);
+            println!(": {}", c.read_u16(i+1));
+            3
+        },
+        Op::Closure => {
+            let count = c.code[i+1] as usize;
+            disassemble_with_data1(op, c.get_line(i), i, count as u8);
+            let mut capture = i + 2;
+            for _ in 0..count {
+                print!("{:05X}       | ", capture);
+                match c.code[capture] {
+                    0 => println!("upvalue {}", c.code[capture+1]),
+                    1 => println!("local {} {}", c.code[capture+1], c.code[capture+2]),
+                    _ => {
+                        println!("local {} {}", c.read_u16(capture+1), c.read_u16(capture+3));
+                        capture += 2;
+                    },
+                }
+                capture += 3;
+            }
+            capture - i
         },
         _ => {
-            disassemble_simple(c.code[i], c.get_line(i), i); 1
+            disassemble_simple(op, c.get_line(i), i); 1
         },
     }
 }
@@ -135,7 +306,7 @@ fn disassemble_with_data1(o: Op, line: usize, loc: usize, data: u8) {
     println!();
 }
 
-fn disassemble_with_data2(o: Op, line: usize, loc: usize, data: u8, data2: u8) {
+fn disassemble_with_data2(o: Op, line: usize, loc: usize, data: usize, data2: usize) {
     disassemble_op(o, line, loc);
     print!(": {}", data);
     print!(": {}", data2);
@@ -151,5 +322,58 @@ pub fn new() -> Chunk {
         code: vec![],
         values: vec![],
         lines: vec![],
+        indexes: HashMap::new(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_constant_encoding() {
+        let mut c = new();
+        let mut ops = Vec::new();
+        for i in 0..70000 {
+            ops.push(c.code.len());
+            assert_eq!(Ok(i as usize), c.add_constant(Value::VInt(i), 0));
+        }
+        for (i, at) in ops.iter().enumerate() {
+            assert_eq!(Value::VInt(i as isize), c.read_constant(*at));
+        }
+        assert_eq!(&[Op::Const1.to_lit(), 255], &c.code[ops[255]..ops[256]]);
+        assert_eq!(&[Op::Const2.to_lit(), 1, 0], &c.code[ops[256]..ops[257]]);
+        assert_eq!(&[Op::Const2.to_lit(), 0x12, 0x34], &c.code[ops[0x1234]..ops[0x1235]]);
+        assert_eq!(&[Op::Const3.to_lit(), 1, 0, 0], &c.code[ops[0x10000]..ops[0x10001]]);
+        assert_eq!(&[Op::Const3.to_lit(), 1, 0x11, 0x6F], &c.code[ops[69999]..]);
+    }
+
+    #[test]
+    fn test_constant_dedup() {
+        let mut c = new();
+        let mut add = |v: Value| c.add_constant(v, 0).expect("should fit");
+        let a = add(Value::VString(String::from("a")));
+        add(Value::VInt(1));
+        assert_eq!(a, add(Value::VString(String::from("a"))));
+        assert_eq!(a, add(Value::VString(String::from("a"))));
+        assert_ne!(add(Value::VFloat(1.0)), add(Value::VInt(1)));
+        assert_ne!(add(Value::VFloat(0.0)), add(Value::VFloat(-0.0)));
+        assert_eq!(add(Value::VSymbol(Symbol::intern("a"))), add(Value::VSymbol(Symbol::intern("a"))));
+        assert_eq!(6, c.values.len());
+    }
+
+    #[test]
+    fn test_jumps() {
+        let mut c = new();
+        let narrow = c.add_jump(Op::Jump, false, 0);
+        let wide = c.add_jump(Op::JumpTrue, true, 0);
+        assert_eq!((2, 4), (c.jump_size(narrow), c.jump_size(wide)));
+        c.code.resize(40000, Op::NoOp.to_lit());
+        assert_eq!(Ok(()), c.patch_jump_to(narrow, 0));
+        assert_eq!(-3, c.read_jump(narrow));
+        assert_eq!(Err(String::from("Can't jump 39997 bytes, the most is 32767")), c.patch_jump(narrow));
+        assert_eq!(Ok(()), c.patch_jump(wide));
+        assert_eq!(Op::JumpTrueWide, Op::from_lit(c.code[wide - 1]));
+        assert_eq!(40000 - 8, c.read_jump(wide));
     }
 }
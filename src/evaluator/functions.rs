@@ -1,11 +1,14 @@
 use crate::ast;
 use crate::bytecode;
+use crate::chunk;
 
 use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::rc::Rc;
 
 const SAME_LINE: usize = 0;
 
-pub type InlineType = fn (&mut super::Evaluator, &ast::List<ast::Atom>) -> ();
+pub type InlineType = fn (&mut super::Evaluator, &ast::List<ast::Atom>) -> Result<(), super::CompileError>;
 
 pub fn get_inlines() -> HashMap<String, InlineType> {
     let mut funs = HashMap::new();
@@ -24,51 +27,100 @@ pub fn get_inlines() -> HashMap<String, InlineType> {
     funs.insert(String::from("cdr"), cdr_inline as InlineType);
     funs.insert(String::from("cons"), cons_inline as InlineType);
 
+    funs.insert(String::from("nth"), nth_inline as InlineType);
+    funs.insert(String::from("assoc"), assoc_inline as InlineType);
+    funs.insert(String::from("conj"), conj_inline as InlineType);
+    funs.insert(String::from("count"), count_inline as InlineType);
+    funs.insert(String::from("subvec"), subvec_inline as InlineType);
+    funs.insert(String::from("get"), get_inline as InlineType);
+    funs.insert(String::from("dissoc"), dissoc_inline as InlineType);
+    funs.insert(String::from("keys"), keys_inline as InlineType);
+    funs.insert(String::from("vals"), vals_inline as InlineType);
+    funs.insert(String::from("contains?"), contains_inline as InlineType);
+    funs.insert(String::from("merge"), merge_inline as InlineType);
+    funs.insert(String::from("union"), union_inline as InlineType);
+    funs.insert(String::from("intersection"), intersection_inline as InlineType);
+    funs.insert(String::from("difference"), difference_inline as InlineType);
+    funs.insert(String::from("subset?"), is_subset_inline as InlineType);
+
+    funs.insert(String::from("symbol?"), is_symbol_inline as InlineType);
+    funs.insert(String::from("symbol->string"), symbol_to_string_inline as InlineType);
+    funs.insert(String::from("string->symbol"), string_to_symbol_inline as InlineType);
+    funs.insert(String::from("keyword?"), is_keyword_inline as InlineType);
+
+    funs.insert(String::from("char->integer"), char_to_integer_inline as InlineType);
+    funs.insert(String::from("integer->char"), integer_to_char_inline as InlineType);
+    funs.insert(String::from("string-ref"), string_ref_inline as InlineType);
+    funs.insert(String::from("char-upcase"), char_upcase_inline as InlineType);
+    funs.insert(String::from("char-alphabetic?"), is_alphabetic_inline as InlineType);
+
+    funs.insert(String::from("throw"), throw_inline as InlineType);
+    funs.insert(String::from("error?"), is_error_inline as InlineType);
+    funs.insert(String::from("error-message"), error_message_inline as InlineType);
+
     // Special forms
     funs.insert(String::from("if"), if_inline as InlineType);
     funs.insert(String::from("quote"), quote_inline as InlineType);
+    funs.insert(String::from("quasiquote"), quasiquote_inline as InlineType);
+    funs.insert(String::from("unquote"), unquote_inline as InlineType);
+    funs.insert(String::from("unquote-splicing"), unquote_inline as InlineType);
     funs.insert(String::from("do"), do_inline as InlineType);
     funs.insert(String::from("def"), def_inline as InlineType);
     funs.insert(String::from("set"), set_inline as InlineType);
+    funs.insert(String::from("let"), let_inline as InlineType);
+    funs.insert(String::from("let*"), let_star_inline as InlineType);
+    funs.insert(String::from("letrec"), letrec_inline as InlineType);
+    funs.insert(String::from("while"), while_inline as InlineType);
+    funs.insert(String::from("loop"), loop_inline as InlineType);
+    funs.insert(String::from("recur"), recur_inline as InlineType);
+    funs.insert(String::from("dotimes"), dotimes_inline as InlineType);
+    funs.insert(String::from("dolist"), dolist_inline as InlineType);
+    funs.insert(String::from("function"), function_inline as InlineType);
+    funs.insert(String::from("macro"), super::macros::macro_inline as InlineType);
+    funs.insert(String::from("try"), try_inline as InlineType);
+    funs.insert(String::from("unwind-protect"), unwind_protect_inline as InlineType);
 
     funs
 }
 
-fn comp_equal_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) {
+fn comp_equal_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
     inline_helper_comp(eve, ast, bytecode::Op::Equal)
 }
-fn comp_less_equal_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) {
+fn comp_less_equal_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
     inline_helper_comp(eve, ast, bytecode::Op::LessEqual)
 }
-fn comp_greater_equal_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) {
+fn comp_greater_equal_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
     inline_helper_comp(eve, ast, bytecode::Op::GreaterEqual)
 }
-fn comp_less_then_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) {
+fn comp_less_then_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
     inline_helper_comp(eve, ast, bytecode::Op::Less)
 }
-fn comp_greater_then_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) {
+fn comp_greater_then_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
     inline_helper_comp(eve, ast, bytecode::Op::Greater)
 }
 
-fn plus_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) {
+fn plus_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
     inline_helper_binary(eve, ast, bytecode::Op::Add)
 }
-fn times_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) {
+fn times_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
     inline_helper_binary(eve, ast, bytecode::Op::Multiply)
 }
-fn divide_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) {
+fn divide_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
     inline_helper_binary(eve, ast, bytecode::Op::Divide)
 }
-fn minus_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) {
+fn minus_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
     if ast.len() > 2 {
         inline_helper_binary(eve, ast, bytecode::Op::Subtract)
     } else {
-        inline_helper_parse_args(eve, ast);
-        eve.chunk.add_op(bytecode::Op::Negate, SAME_LINE);
+        inline_helper_unary(eve, ast, bytecode::Op::Negate)
     }
 }
 
-fn if_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) {
+fn if_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    if ast.len() != 3 && ast.len() != 4 {
+        return eve.error("if needs to have 2 or 3 arguments", ast);
+    }
+    let tail = eve.inline_tail;
     let t1 = ast.tail();
     let t2 = t1.tail();
     let mut false_arg = ast.head();
@@ -79,166 +131,1103 @@ fn if_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) {
         true_arg = false_arg;
         false_arg = None;
     }
-    eve.eval_atom(condition_arg.expect(""), SAME_LINE);
-    eve.chunk.add_op(bytecode::Op::JumpTrue, SAME_LINE);
-    let d1 = eve.chunk.add_op(bytecode::Op::from_lit(0), SAME_LINE);
+    eve.eval_atom(condition_arg.expect(""), SAME_LINE)?;
+    let d1 = jump_helper_add(eve, bytecode::Op::JumpTrue);
     if let Some(arg) = false_arg {
-        eve.eval_atom(arg, SAME_LINE);
+        eve.eval_atom_tail(arg, SAME_LINE, tail)?;
     }
-    eve.chunk.add_op(bytecode::Op::Jump, 0);
-    let d2 = eve.chunk.add_op(bytecode::Op::from_lit(0), SAME_LINE);
-    eve.chunk.replace_instruction(d1, bytecode::Op::from_lit((d2 - d1) as u8));
+    let d2 = jump_helper_add(eve, bytecode::Op::Jump);
+    jump_helper_patch(eve, d1, ast)?;
 
-    eve.eval_atom(true_arg.expect(""), SAME_LINE);
-    let end = eve.chunk.code.len() - 1;
-    eve.chunk.replace_instruction(d2, bytecode::Op::from_lit((end - d2) as u8));
+    eve.eval_atom_tail(true_arg.expect(""), SAME_LINE, tail)?;
+    jump_helper_patch(eve, d2, ast)
 }
 
-fn quote_helper_alist_to_vlist(ast: &ast::Atom) -> bytecode::Value {
+pub fn quote_helper_alist_to_vlist(ast: &ast::Atom) -> bytecode::Value {
     match ast {
         ast::Atom::AFalse => bytecode::Value::VBool(false),
         ast::Atom::ATrue => bytecode::Value::VBool(true),
         ast::Atom::AString(s) => bytecode::Value::VString(s.clone()),
         ast::Atom::AInteger(v) => bytecode::Value::VInt(*v),
-        ast::Atom::AIdentifier(v) => unimplemented!(),
-        ast::Atom::AList(v) => {
+        ast::Atom::AFloat(v) => bytecode::Value::VFloat(*v),
+        ast::Atom::AIdentifier(v) => bytecode::Value::VSymbol(bytecode::Symbol::intern(v)),
+        ast::Atom::AKeyword(v) => bytecode::Value::VKeyword(bytecode::Symbol::intern(v)),
+        ast::Atom::AChar(c) => bytecode::Value::VChar(*c),
+        ast::Atom::AList(v, _) => {
             let mut l: ast::List<bytecode::Value> = ast::List::new();
             for x in v.iter() {
                 l = l.append(quote_helper_alist_to_vlist(x))
             }
-            return bytecode::Value::VList(l)
+            bytecode::Value::VList(l)
+        },
+        ast::Atom::AVector(v) => {
+            bytecode::Value::VVector(ast::List::reverse(v.copy()).iter().map(quote_helper_alist_to_vlist).collect())
+        },
+        ast::Atom::AMap(m) => {
+            bytecode::Value::VMap(collection_helper_pairs(m).iter()
+                                  .map(|(k, v)| (quote_helper_alist_to_vlist(k), quote_helper_alist_to_vlist(v)))
+                                  .collect())
+        },
+        ast::Atom::ASet(l) => {
+            bytecode::Value::VSet(l.iter().map(quote_helper_alist_to_vlist).collect())
+        },
+    }
+}
+
+// [a b c], {k v} and #{a b} evaluate each of their forms, left to right.
+// When they are all literals the whole collection is one constant
+pub fn collection_helper(eve: &mut super::Evaluator, ast: &ast::Atom) -> Result<(), super::CompileError> {
+    if !collection_helper_has_code(ast) {
+        eve.add_constant(quote_helper_alist_to_vlist(ast), SAME_LINE)?;
+        return Ok(());
+    }
+    match ast {
+        ast::Atom::AVector(l) | ast::Atom::ASet(l) => {
+            let empty = match ast {
+                ast::Atom::AVector(_) => bytecode::Value::VVector(ast::Vector::new()),
+                _ => bytecode::Value::VSet(ast::Set::new()),
+            };
+            eve.add_constant(empty, SAME_LINE)?;
+            for elem in ast::List::reverse(l.copy()).iter() {
+                eve.eval_atom(elem, SAME_LINE)?;
+                eve.chunk.add_op(bytecode::Op::Conj, SAME_LINE);
+            }
+        },
+        ast::Atom::AMap(l) => {
+            eve.add_constant(bytecode::Value::VMap(ast::Map::new()), SAME_LINE)?;
+            for (k, v) in collection_helper_pairs(l) {
+                eve.eval_atom(&k, SAME_LINE)?;
+                eve.eval_atom(&v, SAME_LINE)?;
+                eve.chunk.add_op(bytecode::Op::Assoc, SAME_LINE);
+            }
         },
+        _ => (),
     }
+    Ok(())
 }
 
-fn quote_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) {
+fn collection_helper_has_code(ast: &ast::Atom) -> bool {
+    match ast {
+        ast::Atom::AList(_, _) | ast::Atom::AIdentifier(_) => true,
+        ast::Atom::AVector(l) | ast::Atom::AMap(l) | ast::Atom::ASet(l) => l.iter().any(collection_helper_has_code),
+        _ => false,
+    }
+}
+
+// The keys and values of a map literal, in source order
+fn collection_helper_pairs(l: &ast::List<ast::Atom>) -> Vec<(ast::Atom, ast::Atom)> {
+    let forms: Vec<ast::Atom> = ast::List::reverse(l.copy()).iter().cloned().collect();
+    forms.chunks(2).map(|pair| (pair[0].clone(), pair[1].clone())).collect()
+}
+
+fn quote_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
     if ast.len() == 2 {
         if let Some(val) = ast.head() {
-            eve.chunk.add_constant(quote_helper_alist_to_vlist(val), SAME_LINE);
-            return;
+            eve.add_constant(quote_helper_alist_to_vlist(val), SAME_LINE)?;
+            return Ok(());
         }
     }
-    panic!("Error, wrong number of arguments");
+    eve.error("Error, wrong number of arguments", ast)
 }
 
-fn car_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) {
+fn quasiquote_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
     if ast.len() == 2 {
-        inline_helper_parse_args(eve, ast);
-        eve.chunk.add_op(bytecode::Op::Car, SAME_LINE);
-        return;
+        if let Some(val) = ast.head() {
+            return quasiquote_helper(eve, val, 0);
+        }
     }
-    panic!("Error, wrong number of arguments");
+    eve.error("Error, wrong number of arguments", ast)
 }
 
-fn cdr_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) {
-    if ast.len() == 2 {
-        inline_helper_parse_args(eve, ast);
-        eve.chunk.add_op(bytecode::Op::Cdr, SAME_LINE);
-        return;
+fn unquote_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    eve.error("unquote can only be used inside of quasiquote", ast)
+}
+
+// Builds the list back to front, consing each element onto the rest.
+// depth is how many quasiquotes ast is nested in past the outermost one,
+// only unquotes back at depth 0 are evaluated
+fn quasiquote_helper(eve: &mut super::Evaluator, ast: &ast::Atom, depth: usize) -> Result<(), super::CompileError> {
+    if !quasiquote_helper_has_unquote(ast, depth) {
+        eve.add_constant(quote_helper_alist_to_vlist(ast), SAME_LINE)?;
+        return Ok(());
+    }
+    if let ast::Atom::AVector(l) | ast::Atom::ASet(l) = ast {
+        let (empty, kind) = match ast {
+            ast::Atom::AVector(_) => (bytecode::Value::VVector(ast::Vector::new()), "vector"),
+            _ => (bytecode::Value::VSet(ast::Set::new()), "set"),
+        };
+        eve.add_constant(empty, SAME_LINE)?;
+        for elem in ast::List::reverse(l.copy()).iter() {
+            match elem {
+                ast::Atom::AList(inner, _) if depth == 0 && quasiquote_helper_unquoted(inner, "unquote-splicing").is_some() => {
+                    return eve.error(&format!("unquote-splicing can't be used in a {}", kind), inner);
+                },
+                _ => quasiquote_helper(eve, elem, depth)?,
+            }
+            eve.chunk.add_op(bytecode::Op::Conj, SAME_LINE);
+        }
+    }
+    if let ast::Atom::AMap(l) = ast {
+        eve.add_constant(bytecode::Value::VMap(ast::Map::new()), SAME_LINE)?;
+        for (k, v) in collection_helper_pairs(l) {
+            for form in &[k, v] {
+                match form {
+                    ast::Atom::AList(inner, _) if depth == 0 && quasiquote_helper_unquoted(inner, "unquote-splicing").is_some() => {
+                        return eve.error("unquote-splicing can't be used in a map", inner);
+                    },
+                    _ => quasiquote_helper(eve, form, depth)?,
+                }
+            }
+            eve.chunk.add_op(bytecode::Op::Assoc, SAME_LINE);
+        }
+    }
+    if let ast::Atom::AList(l, _) = ast {
+        if depth == 0 {
+            if let Some(val) = quasiquote_helper_unquoted(l, "unquote") {
+                return eve.eval_atom(val, SAME_LINE);
+            }
+        }
+        let inner_depth = quasiquote_helper_depth(l, depth);
+        eve.add_constant(bytecode::Value::VList(ast::List::new()), SAME_LINE)?;
+        for elem in l.iter() {
+            match elem {
+                ast::Atom::AList(inner, _) if inner_depth == 0 && quasiquote_helper_unquoted(inner, "unquote-splicing").is_some() => {
+                    eve.eval_atom(quasiquote_helper_unquoted(inner, "unquote-splicing").expect(""), SAME_LINE)?;
+                    eve.chunk.add_op(bytecode::Op::Splice, SAME_LINE);
+                },
+                _ => {
+                    quasiquote_helper(eve, elem, inner_depth)?;
+                    eve.chunk.add_op(bytecode::Op::Cons, SAME_LINE);
+                },
+            }
+        }
+    }
+    Ok(())
+}
+
+// If l is (name x), returns x
+fn quasiquote_helper_unquoted<'a>(l: &'a ast::List<ast::Atom>, name: &str) -> Option<&'a ast::Atom> {
+    if l.len() == 2 {
+        if let Some(ast::Atom::AIdentifier(s)) = l.tail_tip() {
+            if s == name {
+                return l.head();
+            }
+        }
     }
-    panic!("Error, wrong number of arguments");
+    None
 }
 
-fn cons_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) {
+fn quasiquote_helper_is_unquote(l: &ast::List<ast::Atom>) -> bool {
+    quasiquote_helper_unquoted(l, "unquote").is_some() || quasiquote_helper_unquoted(l, "unquote-splicing").is_some()
+}
+
+// The depth of the elements of l, when l is depth quasiquotes deep
+fn quasiquote_helper_depth(l: &ast::List<ast::Atom>, depth: usize) -> usize {
+    if quasiquote_helper_unquoted(l, "quasiquote").is_some() {
+        depth + 1
+    } else if depth > 0 && quasiquote_helper_is_unquote(l) {
+        depth - 1
+    } else {
+        depth
+    }
+}
+
+fn quasiquote_helper_has_unquote(ast: &ast::Atom, depth: usize) -> bool {
+    match ast {
+        ast::Atom::AList(l, _) => {
+            if depth == 0 && quasiquote_helper_is_unquote(l) {
+                return true;
+            }
+            let inner_depth = quasiquote_helper_depth(l, depth);
+            l.iter().any(|a| quasiquote_helper_has_unquote(a, inner_depth))
+        },
+        ast::Atom::AVector(l) | ast::Atom::AMap(l) | ast::Atom::ASet(l) => {
+            l.iter().any(|a| quasiquote_helper_has_unquote(a, depth))
+        },
+        _ => false,
+    }
+}
+
+fn car_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    inline_helper_unary(eve, ast, bytecode::Op::Car)
+}
+
+fn cdr_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    inline_helper_unary(eve, ast, bytecode::Op::Cdr)
+}
+
+fn cons_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
     if ast.len() == 3 {
-        inline_helper_parse_args(eve, ast);
+        inline_helper_parse_args(eve, ast)?;
         eve.chunk.add_op(bytecode::Op::Cons, SAME_LINE);
-        return;
+        return Ok(());
+    }
+    eve.error("Error, wrong number of arguments", ast)
+}
+
+// (nth coll i)
+fn nth_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    if ast.len() == 3 {
+        inline_helper_parse_args(eve, ast)?;
+        eve.chunk.add_op(bytecode::Op::Nth, SAME_LINE);
+        return Ok(());
+    }
+    eve.error("Error, wrong number of arguments", ast)
+}
+
+// (assoc coll key val*), sets each key in turn, so the collection comes first
+fn assoc_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    if ast.len() < 4 || ast.len() % 2 == 1 {
+        return eve.error("assoc needs a collection and a value for every key", ast);
+    }
+    let args = ast::List::reverse(ast.copy()).tail();
+    eve.eval_atom(args.head().expect(""), SAME_LINE)?;
+    let mut rest = args.tail();
+    while let Some(key) = rest.head() {
+        eve.eval_atom(key, SAME_LINE)?;
+        eve.eval_atom(rest.tail().head().expect(""), SAME_LINE)?;
+        eve.chunk.add_op(bytecode::Op::Assoc, SAME_LINE);
+        rest = rest.tail().tail();
+    }
+    Ok(())
+}
+
+// (conj coll val*)
+fn conj_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    inline_helper_fold(eve, ast, bytecode::Op::Conj)
+}
+
+// (dissoc map key*)
+fn dissoc_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    inline_helper_fold(eve, ast, bytecode::Op::Dissoc)
+}
+
+// (merge map*), later maps win when they share a key
+fn merge_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    inline_helper_fold(eve, ast, bytecode::Op::Merge)
+}
+
+// (union set coll*), the others can be any collection, which makes
+// (union #{} xs) a quick way to de-duplicate a list
+fn union_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    inline_helper_fold(eve, ast, bytecode::Op::Union)
+}
+fn intersection_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    inline_helper_fold(eve, ast, bytecode::Op::Intersection)
+}
+fn difference_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    inline_helper_fold(eve, ast, bytecode::Op::Difference)
+}
+
+// (subset? a b), if everything in a is in b
+fn is_subset_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    if ast.len() == 3 {
+        inline_helper_parse_args(eve, ast)?;
+        eve.chunk.add_op(bytecode::Op::IsSubset, SAME_LINE);
+        return Ok(());
+    }
+    eve.error("Error, wrong number of arguments", ast)
+}
+
+// (get coll key default?), without a default it's false
+fn get_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    if ast.len() == 3 {
+        eve.add_constant(bytecode::Value::VBool(false), SAME_LINE)?;
+    } else if ast.len() != 4 {
+        return eve.error("Error, wrong number of arguments", ast);
+    }
+    inline_helper_parse_args(eve, ast)?;
+    eve.chunk.add_op(bytecode::Op::Get, SAME_LINE);
+    Ok(())
+}
+
+fn contains_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    if ast.len() == 3 {
+        inline_helper_parse_args(eve, ast)?;
+        eve.chunk.add_op(bytecode::Op::Contains, SAME_LINE);
+        return Ok(());
+    }
+    eve.error("Error, wrong number of arguments", ast)
+}
+
+fn keys_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    inline_helper_unary(eve, ast, bytecode::Op::Keys)
+}
+fn vals_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    inline_helper_unary(eve, ast, bytecode::Op::Vals)
+}
+
+fn count_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    inline_helper_unary(eve, ast, bytecode::Op::Count)
+}
+
+// (subvec v start end?), without an end it goes to the end of v
+fn subvec_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    if ast.len() == 3 {
+        eve.add_constant(bytecode::Value::VBool(false), SAME_LINE)?;
+    } else if ast.len() != 4 {
+        return eve.error("Error, wrong number of arguments", ast);
+    }
+    inline_helper_parse_args(eve, ast)?;
+    eve.chunk.add_op(bytecode::Op::Subvec, SAME_LINE);
+    Ok(())
+}
+
+fn is_symbol_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    inline_helper_unary(eve, ast, bytecode::Op::IsSymbol)
+}
+fn symbol_to_string_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    inline_helper_unary(eve, ast, bytecode::Op::SymbolToString)
+}
+fn string_to_symbol_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    inline_helper_unary(eve, ast, bytecode::Op::StringToSymbol)
+}
+
+fn is_keyword_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    inline_helper_unary(eve, ast, bytecode::Op::IsKeyword)
+}
+
+fn char_to_integer_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    inline_helper_unary(eve, ast, bytecode::Op::CharToInteger)
+}
+fn integer_to_char_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    inline_helper_unary(eve, ast, bytecode::Op::IntegerToChar)
+}
+fn char_upcase_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    inline_helper_unary(eve, ast, bytecode::Op::CharUpcase)
+}
+fn is_alphabetic_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    inline_helper_unary(eve, ast, bytecode::Op::IsAlphabetic)
+}
+
+// (string-ref s i), the character at i
+fn string_ref_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    if ast.len() == 3 {
+        inline_helper_parse_args(eve, ast)?;
+        eve.chunk.add_op(bytecode::Op::StringRef, SAME_LINE);
+        return Ok(());
     }
-    panic!("Error, wrong number of arguments");
+    eve.error("Error, wrong number of arguments", ast)
+}
+
+fn throw_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    inline_helper_unary(eve, ast, bytecode::Op::Throw)
+}
+fn is_error_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    inline_helper_unary(eve, ast, bytecode::Op::IsError)
+}
+fn error_message_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    inline_helper_unary(eve, ast, bytecode::Op::ErrorMessage)
 }
 
-fn do_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) {
+// (try body... (catch e handler...)), the body runs like a do. If it throws,
+// the handler runs in its own frame with the thrown value as e
+fn try_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    if ast.len() < 3 {
+        return eve.error("try needs a body and a catch", ast);
+    }
+    let catch = match ast.head() {
+        Some(ast::Atom::AList(c, _)) if matches!(c.tail_tip(), Some(ast::Atom::AIdentifier(s)) if s == "catch") => c,
+        _ => return eve.error("try must end with a catch", ast),
+    };
+    if catch.len() < 3 {
+        return eve.error("catch needs a variable and a handler", ast);
+    }
+    // Source order, with a placeholder on the end for the betweener
+    let handler = ast::List::reverse(catch.append(ast::Atom::AFalse)).tail();
+    let var = match handler.head() {
+        Some(ast::Atom::AIdentifier(s)) => (*s).clone(),
+        _ => return eve.error("catch variable must be an identifier", ast),
+    };
+
+    let mut body = ast::List::new().append(ast::Atom::AIdentifier(String::from("do")));
+    for form in ast::List::reverse(ast.tail()).tail().iter() {
+        body = body.append((*form).clone());
+    }
+
+    let d1 = jump_helper_add(eve, bytecode::Op::Try);
+    eve.try_depth += 1;
+    let res = do_helper(eve, &body, false);
+    eve.try_depth -= 1;
+    res?;
+    eve.chunk.add_op(bytecode::Op::EndTry, SAME_LINE);
+    let d2 = jump_helper_add(eve, bytecode::Op::Jump);
+    jump_helper_patch(eve, d1, ast)?;
+
+    let mut scope = HashMap::new();
+    scope.insert(var, 0);
+    eve.var_stack.push(scope);
+    let res = inline_helper_parse_args_insert_betweener(eve, &handler.tail(), bytecode::Op::Discard1);
+    eve.chunk.add_op(bytecode::Op::DropFrameSaveReturn, SAME_LINE);
+    eve.var_stack.pop();
+    res?;
+
+    jump_helper_patch(eve, d2, ast)
+}
+
+// (unwind-protect body cleanup...), the cleanup runs after the body whether it
+// finishes or errors. Errors carry on to the next handler once it is done
+fn unwind_protect_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    if ast.len() < 3 {
+        return eve.error("unwind-protect needs a body and a cleanup", ast);
+    }
+    let mut body = ast::List::new().append(ast::Atom::AIdentifier(String::from("do")));
+    body = body.append(ast::List::reverse(ast.copy()).tail().head().expect("").clone());
+    let mut cleanup = ast::List::new().append(ast::Atom::AIdentifier(String::from("do")));
+    for form in ast::List::reverse(ast.copy()).tail().tail().iter() {
+        cleanup = cleanup.append((*form).clone());
+    }
+
+    let d1 = jump_helper_add(eve, bytecode::Op::Protect);
+    eve.try_depth += 1;
+    let res = do_helper(eve, &body, false);
+    eve.try_depth -= 1;
+    res?;
+    eve.chunk.add_op(bytecode::Op::EndTry, SAME_LINE);
+    do_helper(eve, &cleanup, false)?;
+    eve.chunk.add_op(bytecode::Op::Discard1, SAME_LINE);
+    let d2 = jump_helper_add(eve, bytecode::Op::Jump);
+    jump_helper_patch(eve, d1, ast)?;
+
+    // The error path gets its own copy of the cleanup
+    do_helper(eve, &cleanup, false)?;
+    eve.chunk.add_op(bytecode::Op::Discard1, SAME_LINE);
+    eve.chunk.add_op(bytecode::Op::Rethrow, SAME_LINE);
+
+    jump_helper_patch(eve, d2, ast)
+}
+
+fn do_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    let tail = eve.inline_tail;
+    do_helper(eve, ast, tail)
+}
+
+// Runs the forms of ast in a new frame, the last one can be a tail call
+fn do_helper(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>, tail: bool) -> Result<(), super::CompileError> {
+    if ast.len() < 2 {
+        return eve.error("do needs at least 1 argument", ast);
+    }
     eve.var_stack.push(HashMap::new());
 
-    let mod_ast = ast::List::reverse(ast.append(ast::Atom::AFalse)).tail();
     eve.chunk.add_op(bytecode::Op::CreateFrame, SAME_LINE);
-    inline_helper_parse_args_insert_betweener(eve, &mod_ast, bytecode::Op::Discard1);
+    let res = do_helper_forms(eve, &ast::List::reverse(ast.copy()).tail(), tail);
     eve.chunk.add_op(bytecode::Op::DropFrameSaveReturn, SAME_LINE);
 
     eve.var_stack.pop();
-    return;
+    res
+}
+
+fn do_helper_forms(eve: &mut super::Evaluator, forms: &ast::List<ast::Atom>, tail: bool) -> Result<(), super::CompileError> {
+    let last = forms.len() - 1;
+    for (i, form) in forms.iter().enumerate() {
+        if i > 0 {
+            eve.chunk.add_op(bytecode::Op::Discard1, SAME_LINE);
+        }
+        eve.eval_atom_tail(form, SAME_LINE, tail && i == last)?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LetKind {
+    Parallel,   // let, values only see the enclosing scope
+    Sequential, // let*, values see the bindings before them
+    Recursive,  // letrec, values see every binding
+}
+
+fn let_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    let_helper(eve, ast, "let", LetKind::Parallel)
+}
+fn let_star_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    let_helper(eve, ast, "let*", LetKind::Sequential)
+}
+fn letrec_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    let_helper(eve, ast, "letrec", LetKind::Recursive)
+}
+
+// (let ((name value)*) body...), the bindings live in a new frame, in order
+fn let_helper(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>,
+              form: &str, kind: LetKind) -> Result<(), super::CompileError> {
+    let tail = eve.inline_tail;
+    if ast.len() < 3 {
+        return eve.error(&format!("{} needs bindings and a body", form), ast);
+    }
+    let forms = ast::List::reverse(ast.copy()).tail();
+    let bindings = let_helper_bindings(eve, ast, form, forms.head().expect(""))?;
+
+    eve.var_stack.push(HashMap::new());
+    eve.chunk.add_op(bytecode::Op::CreateFrame, SAME_LINE);
+    let res = let_helper_body(eve, &bindings, &forms.tail(), kind, tail);
+    eve.chunk.add_op(bytecode::Op::DropFrameSaveReturn, SAME_LINE);
+    eve.var_stack.pop();
+    res
+}
+
+fn let_helper_bindings(eve: &super::Evaluator, ast: &ast::List<ast::Atom>, form: &str,
+                       bindings: &ast::Atom) -> Result<Vec<(String, ast::Atom)>, super::CompileError> {
+    let bindings = match bindings {
+        ast::Atom::AList(l, _) => ast::List::reverse(l.copy()),
+        _ => return eve.error(&format!("{} bindings must be a list", form), ast),
+    };
+    let mut out: Vec<(String, ast::Atom)> = Vec::with_capacity(bindings.len());
+    for binding in bindings.iter() {
+        let (name, value) = match binding {
+            ast::Atom::AList(b, _) if b.len() == 2 => match b.tail().head() {
+                Some(ast::Atom::AIdentifier(name)) => ((*name).clone(), b.head().expect("").clone()),
+                _ => return eve.error(&format!("{} binding must be (name value)", form), ast),
+            },
+            _ => return eve.error(&format!("{} binding must be (name value)", form), ast),
+        };
+        if out.iter().any(|(n, _)| *n == name) {
+            return eve.error(&format!("{} binding [{}] is declared twice", form, name), ast);
+        }
+        out.push((name, value));
+    }
+    Ok(out)
+}
+
+fn let_helper_body(eve: &mut super::Evaluator, bindings: &[(String, ast::Atom)],
+                   body: &ast::List<ast::Atom>, kind: LetKind, tail: bool) -> Result<(), super::CompileError> {
+    if kind == LetKind::Recursive {
+        // Every slot exists before any value is made, so closures can capture them
+        for (i, (name, _)) in bindings.iter().enumerate() {
+            eve.var_stack.last_mut().expect("").insert(name.clone(), i);
+            eve.add_constant(bytecode::Value::VBool(false), SAME_LINE)?;
+        }
+    }
+    for (i, (name, value)) in bindings.iter().enumerate() {
+        function_helper_name(eve, name, value);
+        eve.eval_atom(value, SAME_LINE)?;
+        match kind {
+            LetKind::Parallel => (),
+            LetKind::Sequential => {
+                eve.var_stack.last_mut().expect("").insert(name.clone(), i);
+            },
+            LetKind::Recursive => {
+                local_helper(eve, bytecode::Op::Set, i, 0, name)?;
+                eve.chunk.add_op(bytecode::Op::Discard1, SAME_LINE);
+            },
+        }
+    }
+    if kind == LetKind::Parallel {
+        for (i, (name, _)) in bindings.iter().enumerate() {
+            eve.var_stack.last_mut().expect("").insert(name.clone(), i);
+        }
+    }
+    do_helper_forms(eve, body, tail)
+}
+
+// Builds a list form from atoms in source order
+fn ast_helper_list(atoms: Vec<ast::Atom>) -> ast::Atom {
+    let mut l = ast::List::new();
+    for atom in atoms {
+        l = l.append(atom);
+    }
+    ast::Atom::AList(l, SAME_LINE)
+}
+
+fn ast_helper_ident(s: &str) -> ast::Atom {
+    ast::Atom::AIdentifier(String::from(s))
+}
+
+fn local_helper(eve: &mut super::Evaluator, op: bytecode::Op, loc: usize,
+                stack_back: usize, var: &str) -> Result<(), super::CompileError> {
+    match eve.chunk.add_local(op, loc, stack_back, SAME_LINE) {
+        Ok(()) => Ok(()),
+        Err(msg) => eve.var_error(&msg, var),
+    }
+}
+
+// Adds a jump to patch later, with a wide offset once a narrow one hasn't been enough
+fn jump_helper_add(eve: &mut super::Evaluator, op: bytecode::Op) -> usize {
+    eve.chunk.add_jump(op, eve.wide_jumps, SAME_LINE)
+}
+
+// Points the jump at `at` to the next op compiled
+fn jump_helper_patch(eve: &mut super::Evaluator, at: usize, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    let res = eve.chunk.patch_jump(at);
+    jump_helper_result(eve, res, ast)
+}
+
+// Jumps back to target, which has already been compiled
+fn jump_helper_back(eve: &mut super::Evaluator, target: usize, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    let at = jump_helper_add(eve, bytecode::Op::Jump);
+    let res = eve.chunk.patch_jump_to(at, target);
+    jump_helper_result(eve, res, ast)
+}
+
+fn jump_helper_result(eve: &mut super::Evaluator, res: Result<(), String>,
+                      ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    match res {
+        Ok(()) => Ok(()),
+        Err(msg) => {
+            eve.jump_too_far = !eve.wide_jumps;
+            eve.error(&msg, ast)
+        },
+    }
+}
+
+// (while cond body...), always gives false
+fn while_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    if ast.len() < 3 {
+        return eve.error("while needs a condition and a body", ast);
+    }
+    let forms = ast::List::reverse(ast.copy()).tail();
+    let mut body = ast::List::new().append(ast_helper_ident("do"));
+    for form in forms.tail().iter() {
+        body = body.append((*form).clone());
+    }
+
+    let start = eve.chunk.code.len();
+    eve.eval_atom(forms.head().expect(""), SAME_LINE)?;
+    let d1 = jump_helper_add(eve, bytecode::Op::JumpTrue);
+    let d2 = jump_helper_add(eve, bytecode::Op::Jump);
+    jump_helper_patch(eve, d1, ast)?;
+    do_helper(eve, &body, false)?;
+    eve.chunk.add_op(bytecode::Op::Discard1, SAME_LINE);
+    jump_helper_back(eve, start, ast)?;
+    jump_helper_patch(eve, d2, ast)?;
+    eve.add_constant(bytecode::Value::VBool(false), SAME_LINE)?;
+    Ok(())
+}
+
+// (loop ((name value)*) body...), binds like let*. A recur in the body
+// rebinds them to new values and goes back to the start of the body
+fn loop_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    let tail = eve.inline_tail;
+    if ast.len() < 3 {
+        return eve.error("loop needs bindings and a body", ast);
+    }
+    let forms = ast::List::reverse(ast.copy()).tail();
+    let bindings = let_helper_bindings(eve, ast, "loop", forms.head().expect(""))?;
+    let mut body = ast::List::new().append(ast_helper_ident("do"));
+    for form in forms.tail().iter() {
+        body = body.append((*form).clone());
+    }
+
+    eve.var_stack.push(HashMap::new());
+    eve.chunk.add_op(bytecode::Op::CreateFrame, SAME_LINE);
+    let res = loop_helper_body(eve, &bindings, &body, tail);
+    eve.chunk.add_op(bytecode::Op::DropFrameSaveReturn, SAME_LINE);
+    eve.var_stack.pop();
+    res
+}
+
+fn loop_helper_body(eve: &mut super::Evaluator, bindings: &[(String, ast::Atom)],
+                    body: &ast::List<ast::Atom>, tail: bool) -> Result<(), super::CompileError> {
+    for (i, (name, value)) in bindings.iter().enumerate() {
+        function_helper_name(eve, name, value);
+        eve.eval_atom(value, SAME_LINE)?;
+        eve.var_stack.last_mut().expect("").insert(name.clone(), i);
+    }
+    eve.loops.push(super::Loop {
+        start: eve.chunk.code.len(),
+        depth: eve.var_stack.len() - 1,
+        arity: bindings.len(),
+        try_depth: eve.try_depth,
+        tail: tail && eve.loop_is_tail(),
+    });
+    // The body is compiled as a tail, as that's where recur can go
+    let res = do_helper(eve, body, true);
+    eve.loops.pop();
+    res
 }
 
-fn def_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) {
+fn recur_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    let lp = match eve.loops.last() {
+        Some(lp) => *lp,
+        None => return eve.error("recur must be inside a loop", ast),
+    };
+    if lp.try_depth != eve.try_depth {
+        return eve.error("Can't recur from inside a try or unwind-protect", ast);
+    }
+    if !eve.inline_tail {
+        return eve.error("recur must be in tail position", ast);
+    }
+    if ast.len() - 1 != lp.arity {
+        return eve.error(&format!("recur expects {} values but got {}", lp.arity, ast.len() - 1), ast);
+    }
+    for value in ast::List::reverse(ast.copy()).tail().iter() {
+        eve.eval_atom(value, SAME_LINE)?;
+    }
+    // Closures made in this pass keep the values they saw, rather then the rebound ones
+    let back = eve.var_stack.len() - 1 - lp.depth;
+    let wide_back = match u16::try_from(back) {
+        Ok(b) => b,
+        Err(_) => return eve.error("Can't recur out of more then 65536 nested scopes", ast),
+    };
+    eve.chunk.add_op(bytecode::Op::CloseUpvalues, SAME_LINE);
+    for byte in wide_back.to_be_bytes().iter() {
+        eve.chunk.add_lit(*byte, SAME_LINE);
+    }
+    // The last value is on top, and the body's own frames are dropped
+    for i in (0..lp.arity).rev() {
+        if let Err(msg) = eve.chunk.add_local(bytecode::Op::Set, i, back, SAME_LINE) {
+            return eve.error(&msg, ast);
+        }
+        eve.chunk.add_op(bytecode::Op::Discard1, SAME_LINE);
+    }
+    for _ in 0..back {
+        eve.chunk.add_op(bytecode::Op::DropFrame, SAME_LINE);
+    }
+    jump_helper_back(eve, lp.start, ast)
+}
+
+// (dotimes (name count) body...), runs body with name from 0 to count - 1
+fn dotimes_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    let tail = eve.inline_tail;
+    let (name, count, body) = loop_helper_parse(eve, ast, "dotimes")?;
+    // Names with spaces can't be written in source, so they can't clash
+    let end = ast_helper_ident(" dotimes-end");
+    let mut next = body;
+    next.push(ast_helper_list(vec![ast_helper_ident("recur"),
+        ast_helper_list(vec![ast_helper_ident("+"), ast_helper_ident(&name), ast::Atom::AInteger(1)])]));
+    let expanded = ast_helper_list(vec![
+        ast_helper_ident("let"),
+        ast_helper_list(vec![ast_helper_list(vec![end.clone(), count])]),
+        ast_helper_list(vec![
+            ast_helper_ident("loop"),
+            ast_helper_list(vec![ast_helper_list(vec![ast_helper_ident(&name), ast::Atom::AInteger(0)])]),
+            ast_helper_list(vec![
+                ast_helper_ident("if"),
+                ast_helper_list(vec![ast_helper_ident("<"), ast_helper_ident(&name), end]),
+                ast_helper_list([vec![ast_helper_ident("do")], next].concat()),
+                ast::Atom::AFalse,
+            ]),
+        ]),
+    ]);
+    eve.eval_atom_tail(&expanded, SAME_LINE, tail)
+}
+
+// (dolist (name list) body...), runs body with name as each element of list
+fn dolist_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    let tail = eve.inline_tail;
+    let (name, list, body) = loop_helper_parse(eve, ast, "dolist")?;
+    let rest = ast_helper_ident(" dolist-rest");
+    let mut next = body;
+    next.push(ast_helper_list(vec![ast_helper_ident("recur"),
+        ast_helper_list(vec![ast_helper_ident("cdr"), rest.clone()])]));
+    let expanded = ast_helper_list(vec![
+        ast_helper_ident("loop"),
+        ast_helper_list(vec![ast_helper_list(vec![rest.clone(), list])]),
+        ast_helper_list(vec![
+            ast_helper_ident("if"),
+            ast_helper_list(vec![ast_helper_ident("="), rest.clone(),
+                ast_helper_list(vec![ast_helper_ident("quote"), ast_helper_list(vec![])])]),
+            ast::Atom::AFalse,
+            ast_helper_list([vec![
+                ast_helper_ident("let"),
+                ast_helper_list(vec![ast_helper_list(vec![ast_helper_ident(&name),
+                    ast_helper_list(vec![ast_helper_ident("car"), rest])])]),
+            ], next].concat()),
+        ]),
+    ]);
+    eve.eval_atom_tail(&expanded, SAME_LINE, tail)
+}
+
+// Splits (form (name value) body...) into its parts, with body in source order
+fn loop_helper_parse(eve: &super::Evaluator, ast: &ast::List<ast::Atom>,
+                     form: &str) -> Result<(String, ast::Atom, Vec<ast::Atom>), super::CompileError> {
+    let msg = format!("{} needs (name value) and a body", form);
+    if ast.len() < 3 {
+        return eve.error(&msg, ast);
+    }
+    let forms = ast::List::reverse(ast.copy()).tail();
+    let (name, value) = match forms.head() {
+        Some(ast::Atom::AList(b, _)) if b.len() == 2 => match b.tail().head() {
+            Some(ast::Atom::AIdentifier(name)) => ((*name).clone(), b.head().expect("").clone()),
+            _ => return eve.error(&msg, ast),
+        },
+        _ => return eve.error(&msg, ast),
+    };
+    let body = forms.tail().iter().map(|f| (*f).clone()).collect();
+    Ok((name, value, body))
+}
+
+fn def_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
     if ast.len() != 3 {
-        panic!("def needs to have exactly 2 arguments");
+        return eve.error("def needs to have exactly 2 arguments", ast);
     }
-    eve.eval_atom(ast.head().expect(""), SAME_LINE);
     if let ast::Atom::AIdentifier(s) = ast.tail().head().expect("") {
-        let l = eve.var_stack.len();
-        let v = &mut eve.var_stack[l - 1];
-        v.insert((*s).clone(), v.len());
+        let value = ast.head().expect("");
+        function_helper_name(eve, s, value);
+        eve.eval_atom(value, SAME_LINE)?;
+        if let Some(v) = eve.var_stack.last_mut() {
+            v.insert((*s).clone(), v.len());
+            eve.chunk.add_op(bytecode::Op::Store, SAME_LINE);
+        } else {
+            eve.globals_defined.insert((*s).clone());
+            let name = bytecode::Value::VSymbol(bytecode::Symbol::intern(s));
+            eve.add_constant(name, SAME_LINE)?;
+            eve.chunk.add_op(bytecode::Op::DefGlobal, SAME_LINE);
+        }
+        Ok(())
     } else {
-        panic!("def first argument must be an l-value");
+        eve.error("def first argument must be an l-value", ast)
     }
-    eve.chunk.add_op(bytecode::Op::Store, SAME_LINE);
-    return;
 }
 
-fn set_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) {
+fn set_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
     if ast.len() != 3 {
-        panic!("set needs to have exactly 2 arguments");
+        return eve.error("set needs to have exactly 2 arguments", ast);
     }
-    eve.eval_atom(ast.head().expect(""), SAME_LINE);
     if let ast::Atom::AIdentifier(s) = ast.tail().head().expect("") {
-        let (loc, stack_back) = eve.get_var_stack_loc(s);
-        eve.chunk.add_op(bytecode::Op::Set, SAME_LINE);
-        eve.chunk.add_op(bytecode::Op::from_lit(loc), SAME_LINE);
-        eve.chunk.add_op(bytecode::Op::from_lit(stack_back), SAME_LINE);
+        eve.eval_atom(ast.head().expect(""), SAME_LINE)?;
+        match eve.resolve_var(s)? {
+            super::VarLoc::Local(loc, stack_back) => local_helper(eve, bytecode::Op::Set, loc, stack_back, s)?,
+            super::VarLoc::Upvalue(i) => {
+                eve.chunk.add_op(bytecode::Op::SetUpvalue, SAME_LINE);
+                eve.chunk.add_lit(i, SAME_LINE);
+            },
+            super::VarLoc::Global(name) => {
+                eve.add_constant(bytecode::Value::VSymbol(name), SAME_LINE)?;
+                eve.chunk.add_op(bytecode::Op::SetGlobal, SAME_LINE);
+            },
+        }
+        Ok(())
     } else {
-        panic!("def first argument must be an l-value");
+        eve.error("set first argument must be an l-value", ast)
     }
-    return;
 }
 
-fn inline_helper_comp(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>, opcode: bytecode::Op) {
-    let count = inline_helper_parse_args(eve, ast);
+fn function_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    if ast.len() != 3 {
+        return eve.error("function needs to have exactly 2 arguments", ast);
+    }
+    let params = match ast.tail().head().expect("") {
+        ast::Atom::AList(params, _) => function_helper_params(eve, ast, params)?,
+        _ => return eve.error("function first argument must be a parameter list", ast),
+    };
+    let count = params.required.len() + params.optional.len() + params.rest.iter().count();
     if count > 255 {
-        panic!("Can't have more then 255 values in a comparision");
+        return eve.error("Can't have more then 255 function parameters", ast);
     }
+
+    let name = eve.fn_name.take();
+    let (chunk, captures, entries) = function_helper_compile_body(eve, &params, ast.head().expect(""))?;
+    let f = bytecode::Function {
+        name,
+        arity: params.required.len(),
+        optional: params.optional.len(),
+        rest: params.rest.is_some(),
+        entries,
+        chunk,
+    };
+    let closure = bytecode::Closure {
+        function: Rc::new(f),
+        upvalues: Vec::new(),
+    };
+    eve.add_constant(bytecode::Value::VFunction(Rc::new(closure)), SAME_LINE)?;
+
+    // Functions that capture nothing can be used straight from the constant
+    if !captures.is_empty() {
+        eve.chunk.add_op(bytecode::Op::Closure, SAME_LINE);
+        eve.chunk.add_lit(captures.len() as u8, SAME_LINE);
+        for capture in captures {
+            let bytes = match capture {
+                super::Capture::Upvalue(i) => vec![0, i, 0],
+                super::Capture::Local(loc, stack_back) if loc <= u8::MAX as u16 && stack_back <= u8::MAX as u16 => {
+                    vec![1, loc as u8, stack_back as u8]
+                },
+                super::Capture::Local(loc, stack_back) => {
+                    let mut bytes = vec![2];
+                    bytes.extend_from_slice(&loc.to_be_bytes());
+                    bytes.extend_from_slice(&stack_back.to_be_bytes());
+                    bytes
+                },
+            };
+            for byte in bytes {
+                eve.chunk.add_lit(byte, SAME_LINE);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Functions bound straight to a name are called that in stack traces
+fn function_helper_name(eve: &mut super::Evaluator, name: &str, value: &ast::Atom) {
+    if let ast::Atom::AList(l, _) = value {
+        if let Some(ast::Atom::AIdentifier(f)) = l.tail_tip() {
+            if f == "function" {
+                eve.fn_name = Some(String::from(name));
+            }
+        }
+    }
+}
+
+// A parameter list, like (a b &optional c (d 1) &rest e)
+struct Params {
+    required: Vec<String>,
+    optional: Vec<(String, ast::Atom)>, // With the default, false if none is given
+    rest: Option<String>,
+}
+
+fn function_helper_params(eve: &super::Evaluator, ast: &ast::List<ast::Atom>,
+                          params: &ast::List<ast::Atom>) -> Result<Params, super::CompileError> {
+    let mut out = Params { required: Vec::new(), optional: Vec::new(), rest: None };
+    let mut names: Vec<String> = Vec::new();
+    let mut section = "";
+    let params = ast::List::reverse(params.copy());
+    for param in params.iter() {
+        let (name, default) = match param {
+            ast::Atom::AIdentifier(s) if s == "&optional" || s == "&rest" => {
+                if section == "&rest" || (section == "&optional" && s == "&optional") {
+                    return eve.error(&format!("{} can't come after {}", s, section), ast);
+                }
+                section = s;
+                continue;
+            },
+            ast::Atom::AIdentifier(s) => ((*s).clone(), ast::Atom::AFalse),
+            ast::Atom::AList(l, _) if section == "&optional" && l.len() == 2 => match l.tail().head() {
+                Some(ast::Atom::AIdentifier(s)) => ((*s).clone(), l.head().expect("").clone()),
+                _ => return eve.error("&optional parameters must be a name or (name default)", ast),
+            },
+            _ => return eve.error("function parameters must be identifiers", ast),
+        };
+        if names.contains(&name) {
+            return eve.error(&format!("function parameter [{}] is declared twice", name), ast);
+        }
+        names.push(name.clone());
+        match section {
+            "&optional" => out.optional.push((name, default)),
+            "&rest" if out.rest.is_none() => out.rest = Some(name),
+            "&rest" => return eve.error("&rest must be followed by exactly one parameter", ast),
+            _ => out.required.push(name),
+        }
+    }
+    if section == "&rest" && out.rest.is_none() {
+        return eve.error("&rest must be followed by exactly one parameter", ast);
+    }
+    Ok(out)
+}
+
+// Compiles body into its own chunk, with the parameters as its only locals.
+// Variables of enclosing functions are reached through the returned captures
+fn function_helper_compile_body(eve: &mut super::Evaluator, params: &Params, body: &ast::Atom)
+                                -> Result<(chunk::Chunk, Vec<super::Capture>, Vec<usize>), super::CompileError> {
+    let args = params.required.iter().enumerate().map(|(i, name)| (name.clone(), i)).collect();
+    let enclosing_chunk = std::mem::replace(&mut eve.chunk, chunk::new());
+    let enclosing_vars = std::mem::replace(&mut eve.var_stack, vec![args]);
+    let enclosing_captures = std::mem::take(&mut eve.captures);
+    let enclosing_loops = std::mem::take(&mut eve.loops);
+    eve.enclosing.push((enclosing_vars, enclosing_captures));
+
+    // The body starts on the line of the enclosing form
+    let res = match function_helper_prologue(eve, params) {
+        Ok(entries) => eve.eval_atom_tail(body, eve.line, true).map(|_| entries),
+        Err(e) => Err(e),
+    };
+    eve.chunk.add_op(bytecode::Op::Return, SAME_LINE);
+
+    let (enclosing_vars, enclosing_captures) = eve.enclosing.pop().expect("");
+    eve.var_stack = enclosing_vars;
+    eve.loops = enclosing_loops;
+    let captures = std::mem::replace(&mut eve.captures, enclosing_captures);
+    let chunk = std::mem::replace(&mut eve.chunk, enclosing_chunk);
+    res.map(|entries| (chunk, captures, entries))
+}
+
+// Works out the defaults of optional parameters, each one can use the
+// parameters before it. Returns where to start for each number of them given
+fn function_helper_prologue(eve: &mut super::Evaluator, params: &Params) -> Result<Vec<usize>, super::CompileError> {
+    let mut entries = Vec::with_capacity(params.optional.len() + 1);
+    let mut slot = params.required.len();
+    for (name, default) in &params.optional {
+        entries.push(eve.chunk.code.len());
+        eve.eval_atom(default, eve.line)?;
+        eve.var_stack[0].insert(name.clone(), slot);
+        slot += 1;
+    }
+    if let Some(name) = &params.rest {
+        // Only reached when some optional arguments are missing, so there are none left over
+        if !params.optional.is_empty() {
+            eve.add_constant(bytecode::Value::VList(ast::List::new()), eve.line)?;
+        }
+        eve.var_stack[0].insert(name.clone(), slot);
+    }
+    entries.push(eve.chunk.code.len());
+    Ok(entries)
+}
+
+fn inline_helper_comp(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>, opcode: bytecode::Op) -> Result<(), super::CompileError> {
+    if ast.len() < 2 {
+        return eve.error("Comparisons need at least 1 argument", ast);
+    }
+    if ast.len() > 256 {
+        return eve.error("Can't have more then 255 values in a comparision", ast);
+    }
+    let count = inline_helper_parse_args(eve, ast)?;
     eve.chunk.add_op(opcode, SAME_LINE);
-    eve.chunk.add_op(bytecode::Op::from_lit(count as u8), SAME_LINE);
+    eve.chunk.add_lit(count as u8, SAME_LINE);
+    Ok(())
 }
 
-fn inline_helper_binary(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>, opcode: bytecode::Op) {
-    let count = inline_helper_parse_args(eve, ast);
+fn inline_helper_unary(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>, opcode: bytecode::Op) -> Result<(), super::CompileError> {
+    if ast.len() == 2 {
+        inline_helper_parse_args(eve, ast)?;
+        eve.chunk.add_op(opcode, SAME_LINE);
+        return Ok(());
+    }
+    eve.error("Error, wrong number of arguments", ast)
+}
+
+fn inline_helper_binary(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>, opcode: bytecode::Op) -> Result<(), super::CompileError> {
+    if ast.len() < 2 {
+        return eve.error("Arithmetic needs at least 1 argument", ast);
+    }
+    let count = inline_helper_parse_args(eve, ast)?;
     for _ in 0..(count - 1) {
         eve.chunk.add_op(opcode, SAME_LINE);
     }
+    Ok(())
+}
+
+// Applies op to the first argument and each of the others in turn, so
+// unlike other inlines the arguments are evaluated left to right
+fn inline_helper_fold(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>, op: bytecode::Op) -> Result<(), super::CompileError> {
+    if ast.len() < 2 {
+        return eve.error("Error, wrong number of arguments", ast);
+    }
+    let args = ast::List::reverse(ast.copy()).tail();
+    eve.eval_atom(args.head().expect(""), SAME_LINE)?;
+    for arg in args.tail().iter() {
+        eve.eval_atom(arg, SAME_LINE)?;
+        eve.chunk.add_op(op, SAME_LINE);
+    }
+    Ok(())
 }
 
-fn inline_helper_parse_args(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> usize {
+fn inline_helper_parse_args(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<usize, super::CompileError> {
     let mut iter = ast.iter().peekable();
     let mut count = 0;
     loop {
         let node = iter.next().expect("");
-        if let Some(_) = iter.peek() {
-            eve.eval_atom(node, SAME_LINE);
+        if iter.peek().is_some() {
+            eve.eval_atom(node, SAME_LINE)?;
             count += 1;
         } else {
-            return count;
+            return Ok(count);
         }
     };
 }
 
 fn inline_helper_parse_args_insert_betweener(eve: &mut super::Evaluator,
                                              ast: &ast::List<ast::Atom>,
-                                             op: bytecode::Op) -> usize {
+                                             op: bytecode::Op) -> Result<usize, super::CompileError> {
     let mut iter = ast.iter().peekable();
     let mut count = 0;
     let mut should_insert = false;
     loop {
         let node = iter.next().expect("");
-        if let Some(_) = iter.peek() {
+        if iter.peek().is_some() {
             if should_insert {
                 eve.chunk.add_op(op, SAME_LINE);
             }
-            eve.eval_atom(node, SAME_LINE);
+            eve.eval_atom(node, SAME_LINE)?;
             count += 1;
             should_insert = true;
         } else {
-            return count;
+            return Ok(count);
         }
     };
 }
@@ -0,0 +1,144 @@
+use crate::ast;
+use crate::bytecode;
+use crate::vm;
+
+const SAME_LINE: usize = 0;
+// How many expansions can be compiled inside each other, so a macro that
+// always expands to itself is an error rather then a stack overflow
+const MAX_EXPANSION_DEPTH: usize = 64;
+
+// (macro name func)
+// func is evaluated once, at compile time. Each use of name then calls it
+// with the unevaluated arguments, and compiles what it returns instead
+pub fn macro_inline(eve: &mut super::Evaluator, ast: &ast::List<ast::Atom>) -> Result<(), super::CompileError> {
+    if ast.len() != 3 {
+        return eve.error("macro needs to have exactly 2 arguments", ast);
+    }
+    let name = if let ast::Atom::AIdentifier(s) = ast.tail().head().expect("") {
+        (*s).clone()
+    } else {
+        return eve.error("macro first argument must be an identifier", ast);
+    };
+    let f = match eval_at_compile_time(eve, ast.head().expect(""))? {
+        Ok(f) => f,
+        Err(msg) => return eve.error(&format!("Error evaluating macro: {}", msg), ast),
+    };
+    if let bytecode::Value::VFunction(_) = f {
+        eve.macros.insert(name, f);
+    } else {
+        return eve.error(&format!("macro [{}] must be a function, not {}", name, f.type_of()), ast);
+    }
+    eve.add_constant(bytecode::Value::VBool(false), SAME_LINE)?;
+    Ok(())
+}
+
+pub fn expand(eve: &super::Evaluator, f: bytecode::Value, ast: &ast::List<ast::Atom>) -> Result<ast::Atom, super::CompileError> {
+    let args = ast::List::reverse(ast.copy()).tail();
+    let args = args.iter().map(super::functions::quote_helper_alist_to_vlist).collect();
+    let v = match vm::apply(f, args) {
+        Ok(v) => v,
+        Err(vm::VMError::Runtime(e)) => {
+            return eve.error(&format!("Error expanding macro: {}", e.message), ast)
+        },
+        Err(vm::VMError::Compile(msg)) => {
+            return eve.error(&format!("Error expanding macro: {}", msg), ast)
+        },
+    };
+    match value_to_atom(&v) {
+        Some(atom) => Ok(atom),
+        None => eve.error(&format!("Macro expanded to {}, which is not code", v), ast),
+    }
+}
+
+// Compiles what a macro use in ast expanded to
+pub fn compile_expansion(eve: &mut super::Evaluator, expanded: &ast::Atom, ast: &ast::List<ast::Atom>,
+                         line: usize, tail: bool) -> Result<(), super::CompileError> {
+    if eve.macro_depth >= MAX_EXPANSION_DEPTH {
+        return eve.error(&format!("Macro expansions nested more then {} deep", MAX_EXPANSION_DEPTH), ast);
+    }
+    eve.macro_depth += 1;
+    let res = eve.eval_atom_tail(expanded, line, tail);
+    eve.macro_depth -= 1;
+    res
+}
+
+// Compiles and runs ast on its own, with an evaluator that shares nothing but
+// the macros. It runs in a VM of its own, so globals defined by top level forms
+// don't exist yet, and using one is a compile error.
+// The outer result is for compile errors, the inner one for runtime errors
+fn eval_at_compile_time(eve: &mut super::Evaluator, ast: &ast::Atom) -> Result<Result<bytecode::Value, String>, super::CompileError> {
+    let mut own = compile_on_own(eve, ast, eve.wide_jumps)?;
+    if own.jump_too_far {
+        own = compile_on_own(eve, ast, true)?;
+    }
+    if let Some((name, line)) = own.globals_used.iter().find(|(name, _)| !own.globals_defined.contains(name)) {
+        return Err(super::CompileError {
+            message: format!("Macros can't use the global [{}], it isn't defined until the program runs", name),
+            line: *line,
+            form: Some(ast::Atom::AIdentifier(name.clone())),
+        })
+    }
+    match vm::new(own.chunk).run() {
+        Ok(v) => Ok(Ok(v)),
+        Err(vm::VMError::Runtime(e)) => Ok(Err(e.message)),
+        Err(vm::VMError::Compile(msg)) => Ok(Err(msg)),
+    }
+}
+
+fn compile_on_own(eve: &mut super::Evaluator, ast: &ast::Atom, wide_jumps: bool) -> Result<super::Evaluator, super::CompileError> {
+    let mut own = super::new(wide_jumps);
+    own.macros = std::mem::take(&mut eve.macros);
+    own.line = eve.line;
+    // Still on the same stack, so nesting counts towards the same limits
+    own.depth = eve.depth;
+    own.macro_depth = eve.macro_depth;
+
+    let res = own.eval_atom(ast, SAME_LINE);
+    own.chunk.add_op(bytecode::Op::Return, SAME_LINE);
+    eve.macros = std::mem::take(&mut own.macros);
+    res.map(|_| own)
+}
+
+// The inverse of quoting, turns data built by a macro back into code
+pub fn value_to_atom(v: &bytecode::Value) -> Option<ast::Atom> {
+    Some(match v {
+        bytecode::Value::VInt(i) => ast::Atom::AInteger(*i),
+        bytecode::Value::VFloat(f) => ast::Atom::AFloat(*f),
+        bytecode::Value::VString(s) => ast::Atom::AString(s.clone()),
+        bytecode::Value::VChar(c) => ast::Atom::AChar(*c),
+        bytecode::Value::VBool(true) => ast::Atom::ATrue,
+        bytecode::Value::VBool(false) => ast::Atom::AFalse,
+        bytecode::Value::VSymbol(s) => ast::Atom::AIdentifier(String::from(s.as_str())),
+        bytecode::Value::VKeyword(s) => ast::Atom::AKeyword(String::from(s.as_str())),
+        bytecode::Value::VList(l) => {
+            // ast lists are stored last element first
+            let mut out = ast::List::new();
+            for x in l.iter() {
+                out = out.append(value_to_atom(x)?);
+            }
+            ast::Atom::AList(out, SAME_LINE)
+        },
+        bytecode::Value::VVector(v) => {
+            let mut out = ast::List::new();
+            for x in v.iter() {
+                out = out.append(value_to_atom(x)?);
+            }
+            ast::Atom::AVector(out)
+        },
+        bytecode::Value::VMap(m) => {
+            let mut out = ast::List::new();
+            for (k, v) in m.iter() {
+                out = out.append(value_to_atom(k)?).append(value_to_atom(v)?);
+            }
+            ast::Atom::AMap(out)
+        },
+        bytecode::Value::VSet(s) => {
+            let mut out = ast::List::new();
+            for x in s.iter() {
+                out = out.append(value_to_atom(x)?);
+            }
+            ast::Atom::ASet(out)
+        },
+        _ => return None,
+    })
+}
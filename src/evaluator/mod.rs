@@ -2,89 +2,357 @@ use crate::chunk;
 use crate::bytecode;
 use crate::ast;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 
 mod functions;
+mod macros;
 
-pub fn evaluate(ast: Option<ast::ASTList>) -> Option<chunk::Chunk> {
-    ast.map(|ast| {
-        let mut x = Evaluator {
-            chunk: chunk::new(),
-            inlined: functions::get_inlines(),
-            var_stack: Vec::new(),
-        };
-        x.eval(ast)
-    })
+pub fn evaluate(ast: ast::ASTList) -> Result<chunk::Chunk, CompileError> {
+    let mut x = new(false);
+    match x.eval(ast.copy()) {
+        // Jumps have 2 byte offsets, unless one is too far, then it all starts again with 4 byte ones
+        Err(_) if x.jump_too_far => new(true).eval(ast),
+        res => res,
+    }
+}
+
+fn new(wide_jumps: bool) -> Evaluator {
+    Evaluator {
+        chunk: chunk::new(),
+        inlined: functions::get_inlines(),
+        macros: HashMap::new(),
+        var_stack: Vec::new(),
+        captures: Vec::new(),
+        enclosing: Vec::new(),
+        line: 1,
+        globals_defined: HashSet::new(),
+        globals_used: Vec::new(),
+        fn_name: None,
+        tail: false,
+        inline_tail: false,
+        loops: Vec::new(),
+        try_depth: 0,
+        macro_depth: 0,
+        depth: 0,
+        wide_jumps,
+        jump_too_far: false,
+    }
+}
+
+// How much of the form an error is in gets shown
+const MAX_FORM_CHARS: usize = 80;
+
+// Compiling recurses once per nested form, so deeper code is an error rather then a stack overflow
+const MAX_DEPTH: usize = 384;
+
+#[derive(Debug, Clone)]
+pub struct CompileError {
+    pub message: String,
+    pub line: usize,
+    pub form: Option<ast::Atom>,
 }
 
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)?;
+        if let Some(form) = &self.form {
+            // Forms can be huge, and the start is enough to find them by
+            let form = form.to_string();
+            match form.char_indices().nth(MAX_FORM_CHARS) {
+                Some((end, _)) => write!(f, " in {} ...", form[..end].trim_end())?,
+                None => write!(f, " in {}", form)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+type VarStack = Vec<HashMap<String, usize>>;
+
 pub struct Evaluator {
     chunk: chunk::Chunk,
     inlined: HashMap<String, functions::InlineType>,
-    var_stack: Vec<HashMap<String, usize>>,
+    macros: HashMap<String, bytecode::Value>,
+    var_stack: VarStack,
+    captures: Vec<Capture>,
+    enclosing: Vec<(VarStack, Vec<Capture>)>,
+    line: usize, // Line of the form being compiled
+    globals_defined: HashSet<String>,
+    globals_used: Vec<(String, usize)>,
+    fn_name: Option<String>, // Name for the function a def is about to compile
+    tail: bool, // The next form compiled is the last thing its function (or loop body) does
+    inline_tail: bool, // Whether the inline being compiled is, for if and do to pass on
+    loops: Vec<Loop>, // Loops of the function being compiled, for recur
+    try_depth: usize, // How many try bodies the form being compiled is in
+    macro_depth: usize, // How many macro expansions the form being compiled is in
+    depth: usize, // How many forms the one being compiled is in
+    wide_jumps: bool, // Jumps are compiled with 4 byte offsets
+    jump_too_far: bool, // A jump didn't fit in 2 bytes, so compiling needs to start over with wide ones
+}
+
+// Where a closure gets a captured variable from when it is created
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Capture {
+    Local(u16, u16), // (loc, stack_back) in the enclosing function
+    Upvalue(u8),   // Index into the enclosing function's own captures
+}
+
+// Where recur jumps back to, and which frame holds the loop's bindings
+#[derive(Debug, Clone, Copy)]
+pub struct Loop {
+    start: usize,
+    depth: usize, // Index of the loop's frame in var_stack
+    arity: usize,
+    try_depth: usize,
+    tail: bool, // The loop is the last thing its function does, so calls in its tail can be tail calls
+}
+
+pub enum VarLoc {
+    Local(usize, usize),
+    Upvalue(u8),
+    Global(bytecode::Symbol),
 }
 
 impl Evaluator {
-    fn eval(&mut self, ast: ast::ASTList) -> chunk::Chunk {
-        self.eval_s_expr(ast);
+    // Top level forms run in order, and their defs are globals
+    fn eval(&mut self, ast: ast::ASTList) -> Result<chunk::Chunk, CompileError> {
+        self.eval_s_expr(ast)?;
         self.chunk.add_op(bytecode::Op::Return, 1);
-        std::mem::replace(&mut self.chunk, chunk::new())
+
+        // Globals can be used before their def, so this waits until every def is seen
+        for (name, line) in &self.globals_used {
+            if !self.globals_defined.contains(name) {
+                return Err(CompileError {
+                    message: format!("Var not found: [{}]", name),
+                    line: *line,
+                    form: Some(ast::Atom::AIdentifier(name.clone())),
+                })
+            }
+        }
+        Ok(std::mem::replace(&mut self.chunk, chunk::new()))
     }
 
-    fn eval_s_expr(&mut self, ast: ast::ASTList) {
-        let mut val = ast;
-        while let Some((a, l)) = val.head() {
-            self.eval_atom(a, *l);
-            val = val.tail();
+    fn eval_s_expr(&mut self, ast: ast::ASTList) -> Result<(), CompileError> {
+        if ast.is_empty() {
+            self.add_constant(bytecode::Value::VBool(false), 1)?;
+        }
+        for (i, (a, l)) in ast.iter().enumerate() {
+            if i > 0 {
+                self.chunk.add_op(bytecode::Op::Discard1, *l);
+            }
+            self.eval_atom(a, *l)?;
         }
+        Ok(())
     }
 
-    fn eval_fn(&mut self, ast: &ast::List<ast::Atom>, line: usize) {
+    // Whether the tail of the innermost loop, if there is one, is also its function's tail.
+    // A match rather then is_none_or, which needs a newer compiler
+    fn loop_is_tail(&self) -> bool {
+        match self.loops.last() {
+            Some(lp) => lp.tail,
+            None => true,
+        }
+    }
+
+    // Constants only run out once a chunk has millions of them
+    fn add_constant(&mut self, val: bytecode::Value, line: usize) -> Result<(), CompileError> {
+        match self.chunk.add_constant(val, line) {
+            Ok(_) => Ok(()),
+            Err(message) => Err(CompileError { message, line: self.line, form: None }),
+        }
+    }
+
+    fn error<T>(&self, message: &str, form: &ast::List<ast::Atom>) -> Result<T, CompileError> {
+        Err(CompileError {
+            message: String::from(message),
+            line: self.line,
+            form: Some(ast::Atom::AList(form.copy(), self.line)),
+        })
+    }
+
+    fn eval_fn(&mut self, ast: &ast::List<ast::Atom>, line: usize, tail: bool) -> Result<(), CompileError> {
         let tail_tip = ast.tail_tip();
-        if let Some(ast::Atom::AIdentifier(cmd)) = tail_tip {
-            if let Some(f) = self.inlined.get(cmd) {
-                f(self, ast);
-            } else {
-                unimplemented!("Can't do custom functions yet. Line: {}", line)
-            }
+        match tail_tip {
+            Some(ast::Atom::AIdentifier(cmd)) => {
+                if let Some(f) = self.inlined.get(cmd) {
+                    self.inline_tail = tail;
+                    return f(self, ast);
+                }
+                if let Some(m) = self.macros.get(cmd).cloned() {
+                    let expanded = macros::expand(self, m, ast)?;
+                    return macros::compile_expansion(self, &expanded, ast, line, tail);
+                }
+                self.eval_call(ast, line, tail)
+            },
+            Some(ast::Atom::AList(_, _)) => self.eval_call(ast, line, tail),
+            Some(_) => self.error("Only functions can be called", ast),
+            None => self.error("Can't call an empty list", ast),
+        }
+    }
+
+    // Pushes the function followed by its arguments, in source order
+    fn eval_call(&mut self, ast: &ast::List<ast::Atom>, line: usize, tail: bool) -> Result<(), CompileError> {
+        let count = ast.len() - 1;
+        if count > 255 {
+            return self.error("Can't call a function with more then 255 arguments", ast);
+        }
+        for node in ast::List::reverse(ast.copy()).iter() {
+            self.eval_atom(node, line)?;
+        }
+        // The tail of a loop body is only the function's tail if the loop is too
+        let op = if tail && self.loop_is_tail() {
+            bytecode::Op::TailCall
         } else {
-            panic!("Function is not a function")
+            bytecode::Op::Call
+        };
+        self.chunk.add_op(op, line);
+        self.chunk.add_lit(count as u8, line);
+        Ok(())
+    }
+
+    fn eval_atom_tail(&mut self, ast: &ast::Atom, line: usize, tail: bool) -> Result<(), CompileError> {
+        self.tail = tail;
+        self.eval_atom(ast, line)
+    }
+
+    fn eval_atom(&mut self, ast: &ast::Atom, line: usize) -> Result<(), CompileError> {
+        if self.depth >= MAX_DEPTH {
+            return Err(CompileError {
+                message: String::from("Forms nested too deep"),
+                line: self.line,
+                form: Some(ast.clone()),
+            });
         }
+        self.depth += 1;
+        let res = self.eval_atom_inner(ast, line);
+        self.depth -= 1;
+        res
     }
 
-    fn eval_atom(&mut self, ast: &ast::Atom, line: usize) {
+    fn eval_atom_inner(&mut self, ast: &ast::Atom, line: usize) -> Result<(), CompileError> {
+        let tail = std::mem::take(&mut self.tail);
+        if line != 0 {
+            self.line = line;
+            self.chunk.mark_line(line);
+        }
         match ast {
-            ast::Atom::AList(l) => {
-                self.eval_fn(l, line)
+            ast::Atom::AList(l, 0) => {
+                return self.eval_fn(l, line, tail)
+            }
+            ast::Atom::AList(l, own) => {
+                // Whatever the enclosing form does after this one is back on its line
+                let outer = self.line;
+                self.line = *own;
+                self.chunk.mark_line(*own);
+                let res = self.eval_fn(l, *own, tail);
+                self.line = outer;
+                self.chunk.mark_line(outer);
+                return res
+            }
+            ast::Atom::AVector(_) | ast::Atom::AMap(_) | ast::Atom::ASet(_) => {
+                return functions::collection_helper(self, ast)
             }
             ast::Atom::AInteger(v) => {
-                self.chunk.add_constant(bytecode::Value::VInt(*v), line);
+                self.add_constant(bytecode::Value::VInt(*v), line)?;
+            },
+            ast::Atom::AFloat(v) => {
+                self.add_constant(bytecode::Value::VFloat(*v), line)?;
             },
             ast::Atom::AString(v) => {
-                self.chunk.add_constant(bytecode::Value::VString((*v).clone()), line);
+                self.add_constant(bytecode::Value::VString((*v).clone()), line)?;
+            },
+            ast::Atom::AKeyword(v) => {
+                self.add_constant(bytecode::Value::VKeyword(bytecode::Symbol::intern(v)), line)?;
+            },
+            ast::Atom::AChar(c) => {
+                self.add_constant(bytecode::Value::VChar(*c), line)?;
             },
             ast::Atom::AIdentifier(v) => {
-                let (loc, stack_back) = self.get_var_stack_loc(v);
-                self.chunk.add_op(bytecode::Op::Load, line);
-                self.chunk.add_op(bytecode::Op::from_lit(loc), line);
-                self.chunk.add_op(bytecode::Op::from_lit(stack_back), line);
+                match self.resolve_var(v)? {
+                    VarLoc::Local(loc, stack_back) => {
+                        if let Err(msg) = self.chunk.add_local(bytecode::Op::Load, loc, stack_back, line) {
+                            return self.var_error(&msg, v)
+                        }
+                    },
+                    VarLoc::Upvalue(i) => {
+                        self.chunk.add_op(bytecode::Op::GetUpvalue, line);
+                        self.chunk.add_lit(i, line);
+                    },
+                    VarLoc::Global(name) => {
+                        self.add_constant(bytecode::Value::VSymbol(name), line)?;
+                        self.chunk.add_op(bytecode::Op::GetGlobal, line);
+                    },
+                }
             }
             ast::Atom::ATrue => {
-                self.chunk.add_constant(bytecode::Value::VBool(true), line);
+                self.add_constant(bytecode::Value::VBool(true), line)?;
             },
             ast::Atom::AFalse => {
-                self.chunk.add_constant(bytecode::Value::VBool(false), line);
+                self.add_constant(bytecode::Value::VBool(false), line)?;
             },
         }
+        Ok(())
     }
-    fn get_var_stack_loc(&mut self, var: &String) -> (u8, u8) {
-        let l = self.var_stack.len() - 1;
-        let mut i: i64 = (self.var_stack.len() - 1) as i64;
-        while i >= 0 {
-            if let Some(x) = self.var_stack[i as usize].get(var) {
-                return (*x as u8, (l - (i as usize)) as u8)
+    fn resolve_var(&mut self, var: &str) -> Result<VarLoc, CompileError> {
+        if let Some((loc, stack_back)) = get_var_stack_loc(&self.var_stack, var) {
+            return Ok(VarLoc::Local(loc, stack_back))
+        }
+        if let Some(i) = self.resolve_capture(self.enclosing.len(), var)? {
+            return Ok(VarLoc::Upvalue(i))
+        }
+        // Globals are looked up when the code runs, so they can be defined later
+        self.globals_used.push((String::from(var), self.line));
+        Ok(VarLoc::Global(bytecode::Symbol::intern(var)))
+    }
+
+    // Finds var in the functions enclosing the one at depth `level`, recording
+    // a capture in every function between the definition and the use
+    fn resolve_capture(&mut self, level: usize, var: &str) -> Result<Option<u8>, CompileError> {
+        if level == 0 {
+            return Ok(None)
+        }
+        let capture = if let Some((loc, stack_back)) = get_var_stack_loc(&self.enclosing[level - 1].0, var) {
+            if loc > u16::MAX as usize || stack_back > u16::MAX as usize {
+                return self.var_error("Can't capture a variable past the 65536th local or scope", var)
             }
-            i -= 1;
+            Capture::Local(loc as u16, stack_back as u16)
+        } else {
+            match self.resolve_capture(level - 1, var)? {
+                Some(i) => Capture::Upvalue(i),
+                None => return Ok(None),
+            }
+        };
+
+        let captures = if level == self.enclosing.len() {
+            &mut self.captures
+        } else {
+            &mut self.enclosing[level].1
+        };
+        if let Some(i) = captures.iter().position(|c| *c == capture) {
+            return Ok(Some(i as u8))
+        }
+        if captures.len() >= u8::MAX as usize {
+            return self.var_error("Can't capture more then 255 variables in one function", var)
+        }
+        captures.push(capture);
+        Ok(Some((captures.len() - 1) as u8))
+    }
+
+    fn var_error<T>(&self, message: &str, var: &str) -> Result<T, CompileError> {
+        Err(CompileError {
+            message: String::from(message),
+            line: self.line,
+            form: Some(ast::Atom::AIdentifier(String::from(var))),
+        })
+    }
+}
+
+fn get_var_stack_loc(var_stack: &[HashMap<String, usize>], var: &str) -> Option<(usize, usize)> {
+    for (back, scope) in var_stack.iter().rev().enumerate() {
+        if let Some(x) = scope.get(var) {
+            return Some((*x, back))
         }
-        panic!("Var not found: [{}]", var);
     }
+    None
 }
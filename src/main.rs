@@ -19,41 +19,34 @@ fn main() {
 
     let ast = reader::read(test);
     if debug && show_ast { dbg!(&ast); }
-    if let Some(chunk) = evaluator::evaluate(ast) {
-
-        if debug && show_whole_code {
-            println!("PRINTING WHOLE CODE");
-            chunk.disassemble();
-            println!("END OF WHOLE CODE");
-        }
-        let mut v = vm::new(chunk);
-        v.debug = debug && show_execution;
-        match v.run() {
-            Err(err) => {
-                match err {
-                    vm::VMError::Runtime(msg) => println!("Runtime error: {}", msg),
-                    vm::VMError::Compile(msg) => println!("Compile error: {}", msg),
-                }
-            },
-            Ok(v) => {
-                println!("RETURN: {:?}", v);
+    match ast.map(evaluator::evaluate) {
+        Ok(Ok(chunk)) => {
+            if debug && show_whole_code {
+                println!("PRINTING WHOLE CODE");
+                chunk.disassemble();
+                println!("END OF WHOLE CODE");
             }
-        };
-    } else {
-        println!("Compile error")
+            let mut v = vm::new(chunk);
+            v.debug = debug && show_execution;
+            match v.run() {
+                Err(err) => println!("{}", err),
+                Ok(v) => {
+                    println!("RETURN: {}", v);
+                }
+            };
+        },
+        Ok(Err(err)) => println!("Compile error: {}", err),
+        Err(err) => println!("Read error: {}", err),
     }
 }
 
-fn _test_string(test: &'static str) -> Option<crate::bytecode::Value> {
+fn _test_string(test: &str) -> Option<crate::bytecode::Value> {
     let ast = reader::read(test);
-    if let Some(chunk) = evaluator::evaluate(ast) {
+    if let Ok(Ok(chunk)) = ast.map(evaluator::evaluate) {
         let mut v = vm::new(chunk);
         match v.run() {
             Err(err) => {
-                match err {
-                    vm::VMError::Runtime(msg) => println!("Runtime error: {}", msg),
-                    vm::VMError::Compile(msg) => println!("Compile error: {}", msg),
-                };
+                println!("{}", err);
                 None
             },
             Ok(v) => {
@@ -66,11 +59,30 @@ fn _test_string(test: &'static str) -> Option<crate::bytecode::Value> {
     }
 }
 
+fn _test_runtime_error(test: &str) -> vm::RuntimeError {
+    let chunk = evaluator::evaluate(reader::read(test).expect("should read")).expect("should compile");
+    match vm::new(chunk).run() {
+        Err(vm::VMError::Runtime(e)) => e,
+        _ => panic!("should have a runtime error"),
+    }
+}
+
+fn _test_read_error(test: &str) -> reader::ReadError {
+    reader::read(test).expect_err("should not read")
+}
+
+fn _test_compile_error(test: &str) -> evaluator::CompileError {
+    let ast = reader::read(test).expect("should read");
+    evaluator::evaluate(ast).expect_err("should not compile")
+}
 
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::bytecode::Value::*;
+    use crate::bytecode::Symbol;
+    use crate::bytecode::Op;
+    use crate::ast::List;
 
     #[test]
     fn test_math() {
@@ -81,6 +93,18 @@ mod test {
         assert_eq!(Some(VInt(49)), _test_string("(- (+ 1 2 (* 3 4 (- 9 3 (/ 100 10 10)))) 14)"));
     }
 
+    #[test]
+    fn test_float() {
+        assert_eq!(Some(VFloat(2.75)), _test_string("2.75"));
+        assert_eq!(Some(VFloat(1.0)), _test_string("(+ .5 0.5)"));
+        assert_eq!(Some(VFloat(-0.5)), _test_string("(* -0.5 1)"));
+        assert_eq!(Some(VFloat(1e10)), _test_string("(car '(1e10))"));
+        assert_eq!(Some(VInt(-7)), _test_string("(+ -10 3)"));
+        assert_eq!(Some(VInt(-3)), _test_string("(- 3)"));
+        assert_eq!(Some(VBool(true)), _test_string("(< -1.5 -1 0 .5)"));
+        assert_eq!(Some(VFloat(2.5)), _test_string("(do (macro half (function (x) `(* ,x 0.5))) (half 5))"));
+    }
+
     #[test]
     fn test_strings() {
         assert_eq!(Some(VString(String::from("Hello World"))), _test_string("(+ \"Hello\" \" \" \"World\")"));
@@ -89,11 +113,17 @@ mod test {
     #[test]
     fn test_list() {
         assert_eq!(Some(VInt(1)), _test_string("(car (quote (1 2 3)))"));
-        assert_eq!(Some(VInt(1)), _test_string("(car (cons 1 (quote ()))"));
+        assert_eq!(Some(VInt(1)), _test_string("(car (cons 1 (quote ())))"));
         assert_eq!(Some(VInt(1)), _test_string("(car (cons 1 (cons 2 (cons 3 (cons 4 (quote ()))))))"));
         assert_eq!(Some(VInt(2)), _test_string("(car (cdr (cons 1 (cons 2 (cons 3 (cons 4 (quote ())))))))"));
         assert_eq!(Some(VInt(3)), _test_string("(car (cdr (cdr (cons 1 (cons 2 (cons 3 (cons 4 (quote ()))))))))"));
         assert_eq!(Some(VString(String::from("Hello"))), _test_string("(car (quote (\"Hello\" \"World\")))"));
+
+        // Printing deeply nested lists stops rather then running out of stack
+        let deep = "(loop ((i 0) (l 1)) (if (< i 3000) (recur (+ i 1) (cons l (quote ()))) l))";
+        let code = evaluator::evaluate(reader::read(deep).expect("should read")).expect("should compile");
+        let printed = format!("{}", vm::new(code).run().expect("should run"));
+        assert_eq!(format!("{}(...){}", "(".repeat(256), ")".repeat(256)), printed);
     }
 
     #[test]
@@ -143,4 +173,831 @@ mod test {
         assert_eq!(Some(VInt(7)), _test_string(test_ans7));
         assert_eq!(Some(VInt(7)), _test_string("(do (def a 7) ; comments\n a)"));
     }
+
+    #[test]
+    fn test_function() {
+    let test_apply = r###"
+        (do
+            (def apply (function (f x) (f x)))
+            (def inc (function (x) (+ x 1)))
+            (apply inc 5))
+        "###;
+
+        assert_eq!(Some(VInt(16)), _test_string("((function (x) (* x x)) 4)"));
+        assert_eq!(Some(VInt(16)), _test_string("(do (def sq (function (x) (* x x))) (sq 4))"));
+        assert_eq!(Some(VInt(6)), _test_string("(do (def sub (function (a b) (- a b))) (sub 10 4))"));
+        assert_eq!(Some(VInt(9)), _test_string("((function (x) (do (def y (* x 2)) (+ x y))) 3)"));
+        assert_eq!(Some(VInt(1)), _test_string("((function () 1))"));
+        assert_eq!(Some(VInt(6)), _test_string(test_apply));
+        assert_eq!(None, _test_string("((function (x) x) 1 2)"));
+        assert_eq!(None, _test_string("(1 2)"));
+    }
+
+    #[test]
+    fn test_closure() {
+    let test_counter = r###"
+        (do
+            (def make-counter (function ()
+                (do
+                    (def n 0)
+                    (function () (set n (+ n 1))))))
+            (def a (make-counter))
+            (def b (make-counter))
+            (a)
+            (a)
+            (b)
+            (+ (* 10 (a)) (b)))
+        "###;
+
+    let test_shared = r###"
+        (do
+            (def make-pair (function (n)
+                (do
+                    (def get (function () n))
+                    (def inc (function () (set n (+ n 1))))
+                    (cons get (cons inc (quote ()))))))
+            (def pair (make-pair 5))
+            ((car (cdr pair)))
+            ((car (cdr pair)))
+            ((car pair)))
+        "###;
+
+    let test_open = r###"
+        (do
+            (def x 1)
+            (def f (function () x))
+            (set x 5)
+            (f))
+        "###;
+
+        assert_eq!(Some(VInt(32)), _test_string(test_counter));
+        assert_eq!(Some(VInt(7)), _test_string(test_shared));
+        assert_eq!(Some(VInt(5)), _test_string(test_open));
+        assert_eq!(Some(VInt(7)), _test_string("(do (def add (function (a) (function (b) (+ a b)))) ((add 3) 4))"));
+        assert_eq!(Some(VInt(6)), _test_string("((((function (a) (function (b) (function (c) (+ a b c)))) 1) 2) 3)"));
+    }
+
+    #[test]
+    fn test_macro() {
+    let test_unless = r###"
+        (do
+            (macro unless (function (c a b)
+                (cons (quote if) (cons c (cons b (cons a (quote ())))))))
+            (unless (< 1 2) 10 20))
+        "###;
+
+    let test_defn = r###"
+        (do
+            (macro fn (function (args body)
+                (cons (quote function) (cons args (cons body (quote ()))))))
+            (macro defn (function (name args body)
+                (cons (quote def) (cons name (cons (cons (quote fn) (cons args (cons body (quote ())))) (quote ()))))))
+            (defn sq (x) (* x x))
+            (sq 5))
+        "###;
+
+    let test_unevaluated = r###"
+        (do
+            (macro quote-it (function (x) (cons (quote quote) (cons x (quote ())))))
+            (car (quote-it (a b c))))
+        "###;
+
+        assert_eq!(Some(VInt(20)), _test_string(test_unless));
+        assert_eq!(Some(VInt(25)), _test_string(test_defn));
+        assert_eq!(Some(VSymbol(Symbol::intern("a"))), _test_string(test_unevaluated));
+
+        assert_eq!("Macro expansions nested more then 64 deep",
+                   _test_compile_error("(macro forever (function (x) `(forever ,x)))\n(forever 1)").message);
+        assert_eq!("Macro expansions nested more then 64 deep",
+                   _test_compile_error("(macro grow (function (x) `(+ 1 (grow ,x))))\n(grow 1)").message);
+        assert_eq!("Macro expansions nested more then 64 deep",
+                   _test_compile_error("(macro deep (function (x) `(let ((a 1)) (if a (do (loop ((i 1)) (deep ,x))) 0))))\n(function () (deep 1))").message);
+        // Macros run before any defs do
+        let e = _test_compile_error("(def helper (function (x) x))\n(macro m (function (x) (helper x)))\n(m 1)");
+        assert_eq!("Macros can't use the global [helper], it isn't defined until the program runs", e.message);
+        assert_eq!(2, e.line);
+        // But they can use what they define themselves
+        assert_eq!(Some(VInt(2)), _test_string("(macro m (do (def helper (function (x) x)) (function (x) (helper x))))\n(m 2)"));
+    }
+
+    #[test]
+    fn test_quasiquote() {
+    let test_unless = r###"
+        (do
+            (macro unless (function (c a b) `(if ,c ,b ,a)))
+            (unless (< 1 2) 10 20))
+        "###;
+
+        assert_eq!(Some(VInt(2)), _test_string("(car (cdr '(1 2 3)))"));
+        assert_eq!(Some(VInt(2)), _test_string("(car (cdr `(1 2 3)))"));
+        assert_eq!(Some(VInt(5)), _test_string("(do (def x 5) (car (cdr `(1 ,x 3))))"));
+        assert_eq!(Some(VInt(3)), _test_string("(car (cdr (cdr `(1 ,(+ 1 2) 3))))"));
+        assert_eq!(Some(VInt(3)), _test_string("(do (def xs '(2 3)) (car (cdr (cdr `(1 ,@xs 4)))))"));
+        assert_eq!(Some(VInt(4)), _test_string("(do (def xs '(2 3)) (car (cdr (cdr (cdr `(1 ,@xs 4))))))"));
+        assert_eq!(Some(VInt(7)), _test_string("(do (def x 7) (car (car (cdr `(a (,x))))))"));
+        assert_eq!(Some(VInt(20)), _test_string(test_unless));
+        assert_eq!(None, _test_string("(do (def xs 1) `(,@xs))"));
+
+        // Unquotes belong to the innermost quasiquote, and only the outermost one is evaluated here
+        let nested = _test_string("(do (def x 1) (def ys '(2 3)) `(a `(b ,x ,(c ,x ,@ys) ,@ys)))").expect("should run");
+        assert_eq!("(a (quasiquote (b (unquote x) (unquote (c 1 2 3)) (unquote-splicing ys))))", format!("{}", nested));
+        let nested = _test_string("(do (def x 1) `[`[,x ,,x]])").expect("should run");
+        assert_eq!("[(quasiquote [(unquote x) (unquote 1)])]", format!("{}", nested));
+    }
+
+    #[test]
+    fn test_symbol() {
+        assert_eq!(Some(VSymbol(Symbol::intern("b"))), _test_string("(car (cdr (quote (a b c))))"));
+        assert_eq!(Some(VBool(true)), _test_string("(symbol? 'a)"));
+        assert_eq!(Some(VBool(false)), _test_string("(symbol? \"a\")"));
+        assert_eq!(Some(VBool(true)), _test_string("(= 'a (car '(a b)))"));
+        assert_eq!(Some(VBool(false)), _test_string("(= 'a 'b)"));
+        assert_eq!(Some(VBool(true)), _test_string("(= (string->symbol \"abc\") 'abc)"));
+        assert_eq!(Some(VString(String::from("abc"))), _test_string("(symbol->string 'abc)"));
+        assert_eq!(Some(VBool(true)), _test_string("(= '(a (b 1)) `(a (b ,(- 2 1))))"));
+        assert_eq!(None, _test_string("(symbol->string \"abc\")"));
+
+        let l = _test_string("'(a \"b\" (1 true))").expect("a list");
+        assert_eq!("(a \"b\" (1 true))", format!("{}", l));
+    }
+
+    #[test]
+    fn test_multiple_forms() {
+    let test_program = r###"
+        (def sq (function (x) (* x x)))
+        (macro twice (function (f) `(function (x) (,f (,f x)))))
+        (def quad (twice sq))
+        (quad 3)
+        "###;
+
+        assert_eq!(Some(VInt(3)), _test_string("1 2 3"));
+        assert_eq!(Some(VInt(3)), _test_string("(def x 1) (def y 2) (+ x y)"));
+        assert_eq!(Some(VInt(5)), _test_string("(def x 1) (set x 5) x"));
+        assert_eq!(Some(VInt(81)), _test_string(test_program));
+    }
+
+    #[test]
+    fn test_global() {
+    let test_fact = r###"
+        (def fact (function (n)
+            (if (< n 2) 1 (* n (fact (- n 1))))))
+        (fact 5)
+        "###;
+
+    let test_forward = r###"
+        (def even (function (n) (if (= n 0) true (odd (- n 1)))))
+        (def odd (function (n) (if (= n 0) false (even (- n 1)))))
+        (even 10)
+        "###;
+
+    let test_counter = r###"
+        (def count 0)
+        (def inc (function () (set count (+ count 1))))
+        (inc)
+        (inc)
+        (do (def count 10) (inc))
+        count
+        "###;
+
+        assert_eq!(Some(VInt(1)), _test_string("(def x 1)"));
+        assert_eq!(Some(VInt(120)), _test_string(test_fact));
+        assert_eq!(Some(VBool(true)), _test_string(test_forward));
+        assert_eq!(Some(VInt(3)), _test_string(test_counter));
+        assert_eq!(Some(VInt(2)), _test_string("(if true (def x 2) 0) x"));
+        assert_eq!(None, _test_string("y"));
+        assert_eq!(None, _test_string("(set y 1)"));
+    }
+
+    #[test]
+    fn test_compile_error() {
+        let e = _test_compile_error("(def a)");
+        assert_eq!("def needs to have exactly 2 arguments", e.message);
+        assert_eq!(1, e.line);
+        assert_eq!("line 1: def needs to have exactly 2 arguments in (def a)", format!("{}", e));
+
+        let e = _test_compile_error("1\n\n(car\n 1 2)");
+        assert_eq!(3, e.line);
+        assert_eq!("(car 1 2)", format!("{}", e.form.expect("a form")));
+
+        let e = _test_compile_error("(def f (function () y))\n(f)");
+        assert_eq!("Var not found: [y]", e.message);
+        assert_eq!(1, e.line);
+
+        let comparison = format!("(< {})", "1 ".repeat(300));
+        assert_eq!("Can't have more then 255 values in a comparision", _test_compile_error(&comparison).message);
+
+        assert_eq!("Only functions can be called", _test_compile_error("(1 2)").message);
+        assert_eq!("Can't call an empty list", _test_compile_error("()").message);
+        assert_eq!("if needs to have 2 or 3 arguments", _test_compile_error("(if true)").message);
+        assert_eq!("set first argument must be an l-value", _test_compile_error("(set 1 2)").message);
+        assert_eq!("Arithmetic needs at least 1 argument", _test_compile_error("(+)").message);
+        assert_eq!("Error expanding macro: Value is not car-able",
+                   _test_compile_error("(macro m (function (x) (car x))) (m 1)").message);
+
+        let nested = format!("{}1{}", "(+ 1 ".repeat(500), ")".repeat(500));
+        assert_eq!("Forms nested too deep", _test_compile_error(&nested).message);
+        let nested = format!("{}x{}", "[(do ".repeat(200), ")]".repeat(200));
+        assert_eq!("Forms nested too deep", _test_compile_error(&nested).message);
+    }
+
+    #[test]
+    fn test_read_error() {
+        let e = _test_read_error("(+ 1 2))");
+        assert_eq!("1:8: unexpected )", format!("{}", e));
+
+        let e = _test_read_error("(def a\n  (+ 1 2)");
+        assert_eq!("unclosed ( opened at 1:1", e.message);
+        assert_eq!((2, 9), (e.line, e.column));
+
+        let e = _test_read_error("(print\n   \"abc)");
+        assert_eq!("2:4: unterminated string", format!("{}", e));
+
+        let e = _test_read_error("(a ')");
+        assert_eq!("1:4: expected a form after quote", format!("{}", e));
+
+        let e = _test_read_error("(+ 1 99999999999999999999)");
+        assert_eq!("1:6: Integer literal out of range", format!("{}", e));
+        let e = _test_read_error("(+ 1 -1e400)");
+        assert_eq!("1:6: Float literal out of range", format!("{}", e));
+
+        let e = _test_read_error(&"[".repeat(3000));
+        assert_eq!("1:513: Forms nested too deep", format!("{}", e));
+        let e = _test_read_error(&format!("{}x", "'".repeat(3000)));
+        assert_eq!("1:513: Forms nested too deep", format!("{}", e));
+        assert!(reader::read(&format!("{}{}", "[".repeat(512), "]".repeat(512))).is_ok());
+
+        assert_eq!(Some(VBool(false)), _test_string(""));
+    }
+
+    #[test]
+    fn test_runtime_error() {
+        let e = _test_runtime_error("(def f (function (x) (+ x \"a\")))\n\n(f 1)");
+        assert_eq!("operator Add is not usable with types Int and String", e.message);
+        assert_eq!(Some(Op::Add), e.op);
+        assert_eq!(1, e.line);
+        assert_eq!(vec![
+            vm::TraceFrame { function: Some(String::from("f")), line: 1 },
+            vm::TraceFrame { function: None, line: 3 },
+        ], e.trace);
+        assert_eq!(format!("line 1: {} (in Add at ip {})\n    in f at line 1\n    called from <script> at line 3",
+                           e.message, e.ip), format!("{}", e));
+
+        let e = _test_runtime_error("1\n(car (quote ()))");
+        assert_eq!("Can't take the car of an empty list", e.message);
+        assert_eq!((Some(Op::Car), 2), (e.op, e.line));
+
+        let e = _test_runtime_error("(def g (function (x) x))\n(def h (function () (g)))\n(h)");
+        assert_eq!("<function g> expects 1 arguments but got 0", e.message);
+        assert_eq!(Some(Op::TailCall), e.op);
+        let names: Vec<Option<&str>> = e.trace.iter().map(|t| t.function.as_deref()).collect();
+        assert_eq!(vec![Some("h"), None], names);
+
+        assert_eq!("Value of type Int is not callable", _test_runtime_error("(def a 1)\n(a 2)").message);
+
+        // Lines come from the form itself, not just the top level one it is in
+        let e = _test_runtime_error("(do\n (def a 1)\n\n (car a))");
+        assert_eq!((Some(Op::Car), 4), (e.op, e.line));
+        let e = _test_runtime_error("(def f (function (x) (do\n  (def y (+ x 1))\n  (car\n    y))))\n(f 1)");
+        assert_eq!((Some(Op::Car), 3), (e.op, e.line));
+        assert_eq!(vec![
+            vm::TraceFrame { function: Some(String::from("f")), line: 3 },
+            vm::TraceFrame { function: None, line: 5 },
+        ], e.trace);
+        // After a nested form, the rest of its parent is back on the parent's line
+        let e = _test_runtime_error("(+ (do\n 1)\n \"a\"\n)");
+        assert_eq!((Some(Op::Add), 1), (e.op, e.line));
+
+        let max = "9223372036854775807";
+        let min = "(- -9223372036854775807 1)";
+        assert_eq!("integer overflow in +", _test_runtime_error(&format!("(+ {} 1)", max)).message);
+        assert_eq!("integer overflow in -", _test_runtime_error(&format!("(- {} 1)", min)).message);
+        assert_eq!("integer overflow in *", _test_runtime_error(&format!("(* {} 2)", max)).message);
+        assert_eq!("integer overflow in /", _test_runtime_error(&format!("(/ {} -1)", min)).message);
+        assert_eq!("integer overflow in -", _test_runtime_error(&format!("(- {})", min)).message);
+        assert_eq!(Some(VInt(isize::MAX)), _test_string(&format!("(- -1 {})", min)));
+        assert_eq!(Some(VString(String::from("integer overflow in +"))),
+                   _test_string(&format!("(try (+ {} {}) (catch e (error-message e)))", max, max)));
+    }
+
+    #[test]
+    fn test_try() {
+        assert_eq!(Some(VInt(3)), _test_string("(try (+ 1 2) (catch e 0))"));
+        assert_eq!(Some(VInt(5)), _test_string("(try (throw 5) (catch e e))"));
+        assert_eq!(Some(VInt(6)), _test_string("(+ 1 (try (+ 2 (throw 5)) (catch e e)))"));
+        assert_eq!(Some(VString(String::from("Division by zero"))),
+                   _test_string("(try (/ 1 0) (catch e (error-message e)))"));
+        assert_eq!(Some(VBool(true)), _test_string("(try (car (quote ())) (catch e (error? e)))"));
+        assert_eq!(Some(VBool(false)), _test_string("(try (throw 1) (catch e (error? e)))"));
+        assert_eq!(Some(VString(String::from("operator Add is not usable with types Int and String"))),
+                   _test_string("(try (+ 1 \"a\") (catch e (error-message e)))"));
+
+        // Unwinds out of nested calls and scopes
+        assert_eq!(Some(VInt(12)), _test_string("
+            (def f (function (x) (do (def y 2) (if (= x 0) (throw y) (f (- x 1))))))
+            (do (def a 10)
+                (+ a (try (do (def b 1) (f 5)) (catch e e))))"));
+        // The inner handler rethrows to the outer one
+        assert_eq!(Some(VInt(3)), _test_string("
+            (try (try (throw 1) (catch e (throw (+ e 1)))) (catch e (+ e 1)))"));
+        // Handlers are removed once their body is done
+        assert_eq!(None, _test_string("(try 1 (catch e 2)) (throw 3)"));
+        assert_eq!("Uncaught throw of 3", _test_runtime_error("(try 1 (catch e 2))\n(throw 3)").message);
+        assert_eq!("Division by zero", _test_runtime_error("(try (throw (try (/ 1 0) (catch e e))) (catch e (throw e)))").message);
+
+        assert_eq!("try must end with a catch", _test_compile_error("(try 1 2)").message);
+        assert_eq!("catch variable must be an identifier", _test_compile_error("(try 1 (catch 1 2))").message);
+    }
+
+    #[test]
+    fn test_unwind_protect() {
+        assert_eq!(Some(VInt(1)), _test_string("(unwind-protect 1 2)"));
+        assert_eq!(Some(VList(List::new().append(VInt(1)))), _test_string("
+            (def log (quote ()))
+            (unwind-protect (cons 0 log) (set log (cons 1 log)))
+            log"));
+
+        // Cleanups run innermost first, then the error reaches the try
+        assert_eq!(Some(VString(String::from("123 caught"))), _test_string("
+            (def log \"\")
+            (def f (function () (unwind-protect (/ 1 0) (set log (+ log \"1\")))))
+            (try
+              (unwind-protect
+                (unwind-protect (f) (set log (+ log \"2\")))
+                (set log (+ log \"3\")))
+              (catch e (set log (+ log \" caught\"))))
+            log"));
+
+        // Uncaught errors keep where they came from
+        let e = _test_runtime_error("(def f (function () (car (quote ()))))\n(unwind-protect\n (f)\n 2)");
+        assert_eq!("Can't take the car of an empty list", e.message);
+        assert_eq!(Some(Op::Car), e.op);
+        assert_eq!(Some(String::from("f")), e.trace[0].function);
+
+        // An error in a cleanup replaces the one it was cleaning up after
+        assert_eq!(Some(VInt(2)), _test_string("(try (unwind-protect (throw 1) (throw 2)) (catch e e))"));
+        assert_eq!(Some(VInt(4)), _test_string("
+            (try
+              (unwind-protect (throw 1) (try (throw 2) (catch e 3)))
+              (catch e (+ e 3)))"));
+    }
+
+    #[test]
+    fn test_tail_call() {
+        assert_eq!(Some(VInt(100000)), _test_string("
+            (def count-up (function (n acc) (if (= n 0) acc (count-up (- n 1) (+ acc 1)))))
+            (count-up 100000 0)"));
+        assert_eq!(Some(VBool(true)), _test_string("
+            (def even? (function (n) (if (= n 0) true (do (def m (- n 1)) (odd? m)))))
+            (def odd? (function (n) (if (= n 0) false (even? (- n 1)))))
+            (even? 100000)"));
+        // Captured arguments are kept once the frame is reused
+        assert_eq!(Some(VInt(3)), _test_string("
+            (def f (function (x) ((function (g) (g)) (function () x))))
+            (f 3)"));
+
+        assert_eq!("Stack overflow", _test_runtime_error("
+            (def count-up (function (n) (if (= n 0) 0 (+ 1 (count-up (- n 1))))))
+            (count-up 100000)").message);
+        // A call inside a try is not in tail position, as the handler has to be removed
+        assert_eq!(Some(VInt(7)), _test_string("
+            (def count-down (function (n) (try (if (= n 0) (throw 7) (count-down (- n 1))) (catch e e))))
+            (count-down 1000)"));
+    }
+
+    #[test]
+    fn test_variadic() {
+        let list = |v: Vec<isize>| VList(v.into_iter().rev().fold(List::new(), |l, i| l.append(VInt(i))));
+        assert_eq!(Some(list(vec![1, 2, 3])), _test_string("((function (a &rest more) (cons a more)) 1 2 3)"));
+        assert_eq!(Some(list(vec![])), _test_string("((function (a &rest more) more) 1)"));
+        assert_eq!(Some(list(vec![1, 2])), _test_string("((function (&rest all) all) 1 2)"));
+        assert_eq!(Some(VInt(11)), _test_string("((function (a &optional (b 10)) (+ a b)) 1)"));
+        assert_eq!(Some(VInt(3)), _test_string("((function (a &optional (b 10)) (+ a b)) 1 2)"));
+        assert_eq!(Some(VBool(false)), _test_string("((function (&optional a) a))"));
+        // Defaults can use the parameters before them
+        assert_eq!(Some(VInt(6)), _test_string("((function (a &optional (b (+ a 1)) (c (+ b 1))) (+ a b c)) 1)"));
+        assert_eq!(Some(VInt(12)), _test_string("((function (a &optional (b (+ a 1)) (c (+ b 1))) (+ a b c)) 1 5)"));
+        assert_eq!(Some(list(vec![2, 3, 4])), _test_string("((function (a &optional b &rest c) (cons b c)) 1 2 3 4)"));
+        assert_eq!(Some(list(vec![0])), _test_string("((function (a &optional (b 0) &rest c) (cons b c)) 1)"));
+        assert_eq!(Some(VInt(5)), _test_string("
+            (def f (function (x) (function (&optional (y x)) y)))
+            ((f 5))"));
+
+        // Variadic functions in tail position and as macros
+        assert_eq!(Some(VInt(100000)), _test_string("
+            (def count-up (function (n &optional (acc 0)) (if (= n 0) acc (count-up (- n 1) (+ acc 1)))))
+            (count-up 100000)"));
+        assert_eq!(Some(list(vec![1])), _test_string("
+            (def f (function (n &rest xs) (if (= n 0) xs (f (- n 1) n))))
+            (f 3)"));
+        assert_eq!(Some(VInt(6)), _test_string("
+            (macro add (function (&rest xs) (cons (quote +) xs)))
+            (add 1 2 3)"));
+
+        let e = _test_runtime_error("(def f (function (a b &rest c) a))\n(f 1)");
+        assert_eq!("<function f> expects at least 2 arguments but got 1", e.message);
+        assert_eq!(2, e.line);
+        assert_eq!("<function f> expects 1 to 2 arguments but got 3",
+                   _test_runtime_error("(def f (function (a &optional b) a)) (f 1 2 3)").message);
+        assert_eq!("<function f> expects 1 arguments but got 0",
+                   _test_runtime_error("(def f (function (a) a)) (f)").message);
+
+        assert_eq!("&rest must be followed by exactly one parameter",
+                   _test_compile_error("(function (a &rest) a)").message);
+        assert_eq!("&rest must be followed by exactly one parameter",
+                   _test_compile_error("(function (a &rest b c) a)").message);
+        assert_eq!("&optional can't come after &rest",
+                   _test_compile_error("(function (&rest b &optional c) b)").message);
+        assert_eq!("function parameter [a] is declared twice",
+                   _test_compile_error("(function (a &optional a) a)").message);
+        assert_eq!("&optional parameters must be a name or (name default)",
+                   _test_compile_error("(function (&optional (1 2)) 1)").message);
+    }
+
+    #[test]
+    fn test_vector() {
+        let vector = |v: Vec<isize>| VVector(v.into_iter().map(VInt).collect());
+        assert_eq!(Some(vector(vec![1, 2, 3])), _test_string("[1 2 3]"));
+        assert_eq!(Some(vector(vec![])), _test_string("[]"));
+        assert_eq!(Some(vector(vec![1, 3, 2])), _test_string("(do (def a 2) [1 (+ a 1) a])"));
+        assert_eq!(Some(VVector(vec![VSymbol(Symbol::intern("a")), vector(vec![1])].into_iter().collect())),
+                   _test_string("'[a [1]]"));
+        assert_eq!(Some(vector(vec![1, 5])), _test_string("(do (def x 5) `[1 ,x])"));
+        assert_eq!("[1 [2] \"a\"]", format!("{}", _test_string("[1 [2] \"a\"]").expect("a vector")));
+
+        assert_eq!(Some(VInt(20)), _test_string("(nth [10 20 30] 1)"));
+        assert_eq!(Some(VInt(30)), _test_string("(nth '(10 20 30) 2)"));
+        assert_eq!(Some(vector(vec![1, 9, 3])), _test_string("(assoc [1 2 3] 1 9)"));
+        assert_eq!(Some(vector(vec![1, 2, 3])), _test_string("(assoc [1 2] 2 3)"));
+        assert_eq!(Some(vector(vec![1, 2, 3, 4])), _test_string("(conj [1 2] 3 4)"));
+        assert_eq!(Some(VList(List::new().append(VInt(2)).append(VInt(1)))), _test_string("(conj '(2) 1)"));
+        assert_eq!(Some(VInt(3)), _test_string("(count [1 2 3])"));
+        assert_eq!(Some(VInt(2)), _test_string("(count '(1 2))"));
+        assert_eq!(Some(VInt(5)), _test_string("(count \"hello\")"));
+        assert_eq!(Some(vector(vec![2, 3])), _test_string("(subvec [1 2 3 4] 1 3)"));
+        assert_eq!(Some(vector(vec![3, 4])), _test_string("(subvec [1 2 3 4] 2)"));
+        assert_eq!(Some(vector(vec![2, 9])), _test_string("(conj (subvec [1 2 3 4] 1 2) 9)"));
+        assert_eq!(Some(VBool(true)), _test_string("(= [1 [2]] [1 [2]] (subvec [0 1 [2]] 1))"));
+        assert_eq!(Some(VBool(false)), _test_string("(= [1 2] [1 3])"));
+
+        // The original is left alone
+        assert_eq!(Some(vector(vec![1, 2])), _test_string("(do (def v [1 2]) (assoc v 0 5) (conj v 3) v)"));
+
+        // Big vectors, built and read back a piece at a time
+        assert_eq!(Some(VInt(49995000)), _test_string("
+            (def v [])
+            (dotimes (i 10000) (set v (conj v i)))
+            (def total 0)
+            (dotimes (i (count v)) (set total (+ total (nth v i))))
+            total"));
+        assert_eq!(Some(VInt(-1)), _test_string("
+            (def v [])
+            (dotimes (i 5000) (set v (conj v i)))
+            (nth (assoc v 4321 -1) 4321)"));
+
+        assert_eq!("Index 3 is out of bounds for a Vector of 3", _test_runtime_error("(nth [1 2 3] 3)").message);
+        assert_eq!("Index 5 is out of bounds for a Vector of 3", _test_runtime_error("(assoc [1 2 3] 5 0)").message);
+        assert_eq!("nth needs an index of 0 or more, not -1", _test_runtime_error("(nth [1] -1)").message);
+        assert_eq!("nth needs an Int index, not String", _test_runtime_error("(nth [1] \"a\")").message);
+        assert_eq!("Can't take 2 to 1 of a Vector of 3", _test_runtime_error("(subvec [1 2 3] 2 1)").message);
+        assert_eq!("Can't count a Int", _test_runtime_error("(count 1)").message);
+        assert_eq!("unexpected )", _test_read_error("[1 2)]").message);
+        assert_eq!("unquote-splicing can't be used in a vector", _test_compile_error("`[,@(quote (1))]").message);
+    }
+
+    #[test]
+    fn test_map() {
+        let string = |s: &str| VString(String::from(s));
+        let map = |v: Vec<(bytecode::Value, bytecode::Value)>| VMap(v.into_iter().collect());
+        assert_eq!(Some(map(vec![(string("a"), VInt(1)), (VInt(2), VBool(true))])), _test_string("{\"a\" 1 2 true}"));
+        assert_eq!(Some(map(vec![])), _test_string("{}"));
+        assert_eq!(Some(map(vec![(VSymbol(Symbol::intern("x")), VInt(3))])), _test_string("(do (def x 1) {'x (+ x 2)})"));
+        assert_eq!(Some(map(vec![(VSymbol(Symbol::intern("x")), VInt(3))])), _test_string("'{x 3}"));
+        assert_eq!(Some(map(vec![(VInt(1), VInt(5))])), _test_string("(do (def x 5) `{1 ,x})"));
+        assert_eq!("{1 [2]}", format!("{}", _test_string("{1 [2]}").expect("a map")));
+
+        assert_eq!(Some(VInt(2)), _test_string("(get {\"a\" 1 \"b\" 2} \"b\")"));
+        assert_eq!(Some(VBool(false)), _test_string("(get {\"a\" 1} \"b\")"));
+        assert_eq!(Some(VInt(0)), _test_string("(get {\"a\" 1} \"b\" 0)"));
+        assert_eq!(Some(VInt(2)), _test_string("(get [1 2] 1)"));
+        assert_eq!(Some(VInt(1)), _test_string("(get {[1 2] 1 '(1 2) 2} [1 2])"));
+        assert_eq!(Some(VInt(3)), _test_string("(get {{1 2} 3} {1 2})"));
+        assert_eq!(Some(VInt(4)), _test_string("(get (assoc {1 2} 1 3 5 4) 5)"));
+        assert_eq!(Some(VInt(3)), _test_string("(get (assoc {1 2} 1 3 5 4) 1)"));
+        assert_eq!(Some(map(vec![(VInt(2), VInt(3))])), _test_string("(dissoc {1 1 2 3 4 5} 1 4 6)"));
+        assert_eq!(Some(VList(List::new().append(VInt(1)))), _test_string("(keys {1 2})"));
+        assert_eq!(Some(VList(List::new().append(VInt(2)))), _test_string("(vals {1 2})"));
+        assert_eq!(Some(VInt(10)), _test_string("
+            (def m {1 2 3 4})
+            (+ (car (keys m)) (car (cdr (keys m))) (car (vals m)) (car (cdr (vals m))))"));
+        assert_eq!(Some(VBool(true)), _test_string("(contains? {'a false} 'a)"));
+        assert_eq!(Some(VBool(false)), _test_string("(contains? {'a false} 'b)"));
+        assert_eq!(Some(VBool(true)), _test_string("(contains? [5 6] 1)"));
+        assert_eq!(Some(map(vec![(VInt(1), VInt(2)), (VInt(3), VInt(5)), (VInt(6), VInt(7))])),
+                   _test_string("(merge {1 2 3 4} {3 5} {6 7})"));
+        assert_eq!(Some(VInt(2)), _test_string("(count {1 2 3 4})"));
+        assert_eq!(Some(VBool(true)), _test_string("(= {1 2 3 4} {3 4 1 2})"));
+        assert_eq!(Some(VBool(false)), _test_string("(= {1 2} {1 3})"));
+        // Numbers that are = are the same key
+        assert_eq!(Some(VInt(1)), _test_string("(count {1 1 1.0 2})"));
+        assert_eq!(Some(VBool(true)), _test_string("(= (get {1 :x} 1.0) :x)"));
+        assert_eq!(Some(VBool(true)), _test_string("(contains? #{1} 1.0)"));
+        assert_eq!(Some(VBool(false)), _test_string("(contains? #{1} 1.5)"));
+        assert_eq!(Some(VBool(true)), _test_string("(= [1 {2.0 3}] [1.0 {2 3.0}])"));
+        assert_eq!(Some(VBool(false)), _test_string("(= 9007199254740993 9007199254740992.0)"));
+
+        // The original is left alone
+        assert_eq!(Some(map(vec![(VInt(1), VInt(2))])), _test_string("(do (def m {1 2}) (assoc m 3 4) (dissoc m 1) m)"));
+
+        assert_eq!(Some(VInt(3998000)), _test_string("
+            (def m {})
+            (dotimes (i 2000) (set m (assoc m i (* i 2))))
+            (def total 0)
+            (dotimes (i 2000) (set total (+ total (get m i))))
+            total"));
+
+        assert_eq!("assoc needs a Vector or Map, not Int", _test_runtime_error("(assoc 1 2 3)").message);
+        assert_eq!("merge needs Maps, not Vector", _test_runtime_error("(merge {} [])").message);
+        assert_eq!("assoc needs a collection and a value for every key", _test_compile_error("(assoc {} 1)").message);
+        assert_eq!("map needs a value for every key", _test_read_error("{1 2 3}").message);
+    }
+
+    #[test]
+    fn test_set() {
+        let set = |v: Vec<isize>| VSet(v.into_iter().map(VInt).collect());
+        assert_eq!(Some(set(vec![1, 2, 3])), _test_string("#{1 2 3}"));
+        assert_eq!(Some(set(vec![1, 2])), _test_string("#{1 2 1}"));
+        assert_eq!(Some(set(vec![])), _test_string("#{}"));
+        assert_eq!(Some(set(vec![1, 3])), _test_string("(do (def a 2) #{1 (+ a 1)})"));
+        assert_eq!(Some(VSet(vec![VSymbol(Symbol::intern("a"))].into_iter().collect())), _test_string("'#{a}"));
+        assert_eq!(Some(set(vec![5])), _test_string("(do (def x 5) `#{,x})"));
+        assert_eq!("#{[1]}", format!("{}", _test_string("#{[1]}").expect("a set")));
+        // # on its own is still part of a name
+        assert_eq!(Some(VInt(1)), _test_string("(do (def #a 1) #a)"));
+
+        assert_eq!(Some(set(vec![1, 2, 3, 4])), _test_string("(union #{1 2} #{2 3} #{4})"));
+        assert_eq!(Some(set(vec![1, 2, 3])), _test_string("(union #{} '(1 2 1 3 2) [3 3])"));
+        assert_eq!(Some(set(vec![2, 3])), _test_string("(intersection #{1 2 3} #{2 3 4})"));
+        assert_eq!(Some(set(vec![3])), _test_string("(intersection #{1 2 3} #{2 3 4} #{3})"));
+        assert_eq!(Some(set(vec![1])), _test_string("(difference #{1 2 3} #{2} #{3})"));
+        assert_eq!(Some(VBool(true)), _test_string("(subset? #{1 2} #{1 2 3})"));
+        assert_eq!(Some(VBool(false)), _test_string("(subset? #{1 4} #{1 2 3})"));
+        assert_eq!(Some(VBool(true)), _test_string("(contains? #{\"a\" [1]} [1])"));
+        assert_eq!(Some(VBool(false)), _test_string("(contains? #{\"a\"} \"b\")"));
+        assert_eq!(Some(set(vec![1, 2])), _test_string("(conj #{1} 2 1)"));
+        assert_eq!(Some(VInt(3)), _test_string("(count #{1 2 3})"));
+        assert_eq!(Some(VBool(true)), _test_string("(= #{1 2 3} #{3 2 1})"));
+        assert_eq!(Some(VInt(1)), _test_string("(get {#{1 2} 1} #{2 1})"));
+
+        assert_eq!("intersection needs Sets, not List", _test_runtime_error("(intersection #{1} '(1))").message);
+        assert_eq!("union needs a Set, not Vector", _test_runtime_error("(union [1] #{1})").message);
+        assert_eq!("unclosed #{ opened at 1:1", _test_read_error("#{1 2").message);
+    }
+
+    #[test]
+    fn test_keyword() {
+        let kw = |s: &str| VKeyword(Symbol::intern(s));
+        assert_eq!(Some(kw("a")), _test_string(":a"));
+        assert_eq!(Some(kw("a")), _test_string("':a"));
+        assert_eq!(":red", format!("{}", _test_string(":red").expect("a keyword")));
+        assert_eq!(Some(VBool(true)), _test_string("(= :a :a)"));
+        assert_eq!(Some(VBool(false)), _test_string("(= :a :b)"));
+        // Not the same as a symbol or string with the same name
+        assert_eq!(Some(VBool(false)), _test_string("(= :a 'a)"));
+        assert_eq!(Some(VBool(false)), _test_string("(= :a \"a\")"));
+        assert_eq!(Some(VBool(true)), _test_string("(keyword? :a)"));
+        assert_eq!(Some(VBool(false)), _test_string("(keyword? 'a)"));
+        assert_eq!(Some(VBool(false)), _test_string("(symbol? :a)"));
+
+        assert_eq!(Some(VInt(2)), _test_string("(get {:a 1 :b 2} :b)"));
+        assert_eq!(Some(VInt(3)), _test_string("(get {:a 1 'a 3} 'a)"));
+        assert_eq!(Some(VString(String::from("stop"))), _test_string("
+            (def describe (function (light)
+              (if (= light :red) \"stop\" (if (= light :green) \"go\" \"slow\"))))
+            (describe :red)"));
+        assert_eq!(Some(kw("b")), _test_string("
+            (macro tag (function (x) `(if ,x :a :b)))
+            (tag false)"));
+
+        assert_eq!("function parameters must be identifiers", _test_compile_error("(function (:a) 1)").message);
+    }
+
+    #[test]
+    fn test_char() {
+        assert_eq!(Some(VChar('a')), _test_string("#\\a"));
+        assert_eq!(Some(VChar(' ')), _test_string("#\\space"));
+        assert_eq!(Some(VChar('\n')), _test_string("#\\newline"));
+        assert_eq!(Some(VChar('A')), _test_string("#\\x41"));
+        assert_eq!(Some(VChar(')')), _test_string("(do #\\))"));
+        assert_eq!(Some(VChar('b')), _test_string("'#\\b"));
+        assert_eq!("#\\space #\\a #\\x1", format!("{} {} {}", VChar(' '), VChar('a'), VChar('\u{1}')));
+
+        assert_eq!(Some(VInt(97)), _test_string("(char->integer #\\a)"));
+        assert_eq!(Some(VChar('A')), _test_string("(integer->char 65)"));
+        assert_eq!(Some(VChar('é')), _test_string("(string-ref \"café\" 3)"));
+        assert_eq!(Some(VChar('A')), _test_string("(char-upcase #\\a)"));
+        assert_eq!(Some(VChar('1')), _test_string("(char-upcase #\\1)"));
+        assert_eq!(Some(VBool(true)), _test_string("(char-alphabetic? #\\z)"));
+        assert_eq!(Some(VBool(false)), _test_string("(char-alphabetic? #\\space)"));
+        assert_eq!(Some(VBool(true)), _test_string("(= #\\a #\\a)"));
+        assert_eq!(Some(VBool(true)), _test_string("(< #\\a #\\b #\\c)"));
+        assert_eq!(Some(VBool(false)), _test_string("(= #\\a \"a\")"));
+        assert_eq!(Some(VInt(2)), _test_string("(get {#\\a 1 #\\b 2} #\\b)"));
+
+        // Counting the letters of a string
+        assert_eq!(Some(VInt(5)), _test_string("
+            (def s \"Hi, there!\")
+            (def letters 0)
+            (dotimes (i (count s))
+              (if (char-alphabetic? (string-ref s i)) (set letters (+ letters 1)) letters))
+            (- letters 2)"));
+
+        assert_eq!("No character has the code 55296", _test_runtime_error("(integer->char 55296)").message);
+        assert_eq!("No character has the code -1", _test_runtime_error("(integer->char -1)").message);
+        assert_eq!("Index 3 is out of bounds for a String of 3", _test_runtime_error("(string-ref \"abc\" 3)").message);
+        assert_eq!("char->integer needs a Char, not String", _test_runtime_error("(char->integer \"a\")").message);
+        assert_eq!("unknown character #\\spaces", _test_read_error("#\\spaces").message);
+    }
+
+    #[test]
+    fn test_let() {
+        assert_eq!(Some(VInt(3)), _test_string("(let ((a 1) (b 2)) (+ a b))"));
+        assert_eq!(Some(VInt(3)), _test_string("(let ((a 1)) (def b 2) (+ a b))"));
+        assert_eq!(Some(VInt(12)), _test_string("(do (def a 10) (let ((a 1) (b a)) (+ a b 1)))"));
+        assert_eq!(Some(VInt(3)), _test_string("(let* ((a 1) (b (+ a 1))) (+ a b))"));
+        assert_eq!(Some(VInt(5)), _test_string("(do (def x 5) (let ((y 1)) (def x 7) y) x)"));
+        assert_eq!(Some(VInt(5)), _test_string("(let ((a 1)) (let ((a 2) (b a)) (+ a b a)))"));
+        assert_eq!(Some(VBool(true)), _test_string("
+            (letrec ((even? (function (n) (if (= n 0) true (odd? (- n 1)))))
+                     (odd? (function (n) (if (= n 0) false (even? (- n 1))))))
+              (even? 10))"));
+        assert_eq!(Some(VInt(6)), _test_string("
+            (def f (function (x) (let ((y (+ x 1))) (let* ((z (+ y 1)) (g (function () (+ x y z)))) (g)))))
+            (f 1)"));
+
+        assert_eq!("Var not found: [b]", _test_compile_error("(let ((a 1)) 1) b").message);
+        assert_eq!("Var not found: [a]", _test_compile_error("(let ((a 1) (b a)) b)").message);
+        assert_eq!("let binding must be (name value)", _test_compile_error("(let ((a)) 1)").message);
+        assert_eq!("let* binding [a] is declared twice", _test_compile_error("(let* ((a 1) (a 2)) a)").message);
+        assert_eq!("letrec needs bindings and a body", _test_compile_error("(letrec ())").message);
+    }
+
+    #[test]
+    fn test_loops() {
+        assert_eq!(Some(VInt(45)), _test_string("
+            (let ((i 0) (sum 0))
+              (while (< i 10)
+                (set sum (+ sum i))
+                (set i (+ i 1)))
+              sum)"));
+        assert_eq!(Some(VBool(false)), _test_string("(while false 1)"));
+
+        assert_eq!(Some(VInt(120)), _test_string("
+            (loop ((n 5) (acc 1))
+              (if (= n 0) acc (recur (- n 1) (* acc n))))"));
+        // recur from inside nested frames
+        assert_eq!(Some(VInt(55)), _test_string("
+            (def sum (function (to)
+              (loop ((i 0) (acc 0))
+                (def next (+ i 1))
+                (if (> i to) acc (let ((a (+ acc i))) (do (def b next) (recur b a)))))))
+            (sum 10)"));
+        assert_eq!(Some(VInt(100000)), _test_string("(loop ((i 0)) (if (< i 100000) (recur (+ i 1)) i))"));
+        assert_eq!(Some(VInt(6)), _test_string("
+            (loop ((i 0) (total 0))
+              (if (= i 3) total
+                (recur (+ i 1) (+ total (loop ((j 0)) (if (< j i) (recur (+ j 1)) (+ j 1)))))))"));
+
+        // recur in the tail of an if, do or let inside the body
+        assert_eq!(Some(VInt(3)), _test_string("
+            (loop ((i 0))
+              (if (< i 3) (do (def j (+ i 1)) (let ((k j)) (recur k))) i))"));
+        // A loop that isn't the function's tail still returns to it
+        assert_eq!(Some(VInt(103)), _test_string("
+            (def f (function (n) n))
+            (def g (function () (+ 100 (loop ((i 0)) (if (< i 3) (recur (+ i 1)) (f i))))))
+            (g)"));
+
+        // Closures made in one pass keep that pass's bindings
+        assert_eq!(Some(VInt(2)), _test_string("
+            (loop ((i 0) (fs '()))
+              (if (< i 3) (recur (+ i 1) (cons (function () i) fs)) ((car fs))))"));
+        assert_eq!(Some(VInt(1)), _test_string("
+            (let ((fs '()))
+              (dotimes (i 3) (set fs (cons (function () i) fs)))
+              ((car (cdr fs))))"));
+
+        assert_eq!(Some(VInt(10)), _test_string("(let ((sum 0)) (dotimes (i 5) (set sum (+ sum i))) sum)"));
+        assert_eq!(Some(VBool(false)), _test_string("(dotimes (i 3) i)"));
+        assert_eq!(Some(VInt(6)), _test_string("(let ((sum 0)) (dolist (x (quote (1 2 3))) (set sum (+ sum x))) sum)"));
+        assert_eq!(Some(VInt(0)), _test_string("(let ((sum 0)) (dolist (x (quote ())) (set sum 1)) sum)"));
+
+        assert_eq!("recur must be inside a loop", _test_compile_error("(recur 1)").message);
+        assert_eq!("recur must be inside a loop",
+                   _test_compile_error("(loop ((i 0)) (function () (recur 1)))").message);
+        assert_eq!("recur expects 1 values but got 2", _test_compile_error("(loop ((i 0)) (recur 1 2))").message);
+        assert_eq!("Can't recur from inside a try or unwind-protect",
+                   _test_compile_error("(loop ((i 0)) (try (recur 1) (catch e e)))").message);
+        assert_eq!("Can't recur from inside a try or unwind-protect",
+                   _test_compile_error("(loop ((i 0)) (unwind-protect (recur 1) 2))").message);
+        assert_eq!("recur must be in tail position",
+                   _test_compile_error("(loop ((i 0)) (if (< i 3) (+ 100 (recur (+ i 1))) i))").message);
+        assert_eq!("recur must be in tail position",
+                   _test_compile_error("(loop ((i 0)) (recur (+ i 1)) i)").message);
+        assert_eq!("recur must be in tail position",
+                   _test_compile_error("(loop ((i 0)) (while (< i 3) (recur (+ i 1))))").message);
+        assert_eq!("dotimes needs (name value) and a body", _test_compile_error("(dotimes i 1)").message);
+    }
+
+    #[test]
+    fn test_long_jumps() {
+        // Both branches are far longer than a 1 byte offset can reach
+        let sum = format!("(+ {})", "1 ".repeat(200));
+        assert_eq!(Some(VInt(200)), _test_string(&format!("(if true {} 0)", sum)));
+        assert_eq!(Some(VInt(200)), _test_string(&format!("(if false 0 {})", sum)));
+        assert_eq!(Some(VInt(200)), _test_string(&format!("(try {} (catch e e))", sum)));
+        assert_eq!(Some(VInt(400)), _test_string(&format!("
+            (let ((i 0) (total 0))
+              (while (< i 2) (set total (+ total {})) (set i (+ i 1)))
+              total)", sum)));
+        assert_eq!(Some(VInt(400)), _test_string(&format!("
+            (loop ((i 0) (total 0)) (if (= i 2) total (recur (+ i 1) (+ total {}))))", sum)));
+
+        // Past what 2 bytes can reach, the wide jumps are used
+        let ones = "1 ".repeat(20000);
+        assert_eq!(Some(VInt(20000)), _test_string(&format!("(if true (+ {}) 0)", ones)));
+        assert_eq!(Some(VInt(20000)), _test_string(&format!("(if false 0 (+ {}))", ones)));
+        assert_eq!(Some(VInt(20000)), _test_string(&format!("(try (+ {}) (catch e e))", ones)));
+        assert_eq!(Some(VInt(40000)), _test_string(&format!("
+            (loop ((i 0) (total 0)) (if (= i 2) total (recur (+ i 1) (+ total {}))))", ones)));
+        assert_eq!(Some(VInt(40000)), _test_string(&format!("
+            (def f (function (n) (let ((total 0))
+              (while (> n 0) (set total (+ total {})) (set n (- n 1)))
+              total)))
+            (f 2)", ones)));
+
+        // Errors only show the start of a huge form
+        let e = _test_compile_error(&format!("(if true (+ {}) 0 1)", ones));
+        let shown = format!("{}", e);
+        assert!(shown.len() < 200 && shown.ends_with(" ..."), "{}", shown);
+        let start = format!("(if true (+ {}", ones);
+        assert_eq!(format!("line 1: {} in {} ...", e.message, start[..80].trim_end()), shown);
+    }
+
+    #[test]
+    fn test_many_locals() {
+        // Slots past 255 need the wide ops, and must not alias slot 0
+        let defs: String = (0..300).map(|i| format!("(def a{} z) ", i)).collect();
+        assert_eq!(Some(VInt(12)), _test_string(&format!("(let ((z 7)) {} (set a299 5) (+ a299 a0))", defs)));
+
+        // As do scopes nested further than 255 frames
+        let mut nested = String::from("(do (def x 1) ");
+        for i in 0..300 {
+            nested += &format!("(do (def y{} x) ", i);
+        }
+        nested += "(set x (+ x y299 y0)) x";
+        nested += &")".repeat(301);
+        assert_eq!(Some(VInt(3)), _test_string(&nested));
+
+        let loop_vars: String = (0..300).map(|i| format!("(v{} z) ", i)).collect();
+        assert_eq!(Some(VInt(7)), _test_string(&format!(
+            "(let ((z 7) (o 1)) (loop ({}) (if (= v0 o) v299 (recur {} v0))))", loop_vars, "o ".repeat(299))));
+
+        let defs: String = (0..65537).map(|i| format!("(def b{} a) ", i)).collect();
+        assert_eq!("Can't have more then 65536 locals or nested scopes",
+                   _test_compile_error(&format!("(let ((a 1)) {} b65536)", defs)).message);
+
+        // Closures capture those with a wide encoding too
+        let defs: String = (0..301).map(|i| format!("(def c{} {}) ", i, i)).collect();
+        assert_eq!(Some(VInt(600)), _test_string(&format!(
+            "(let ((a 1)) {} (def f (function () (set c300 (+ c300 c300)))) (f) c300)", defs)));
+        assert_eq!(Some(VInt(301)), _test_string(&format!(
+            "(let ((a 1)) {} ((function () ((function () (+ c300 c1))))))", defs)));
+        let mut nested = String::from("(do (def x 1) ");
+        for i in 0..300 {
+            nested += &format!("(do (def y{} x) ", i);
+        }
+        nested += "(def f (function () (+ x y299))) (set x 5) (f)";
+        nested += &")".repeat(301);
+        assert_eq!(Some(VInt(6)), _test_string(&nested));
+    }
+
+    #[test]
+    fn test_many_constants() {
+        let sum = format!("(+ {})", (0..5000).map(|i| i.to_string()).collect::<Vec<String>>().join(" "));
+        assert_eq!(Some(VInt(12497500)), _test_string(&sum));
+
+        // Each string is its own constant, past the ones the numbers used
+        let strings: String = (0..3000).map(|i| format!("\"s{}\" ", i)).collect();
+        let joined: String = (0..3000).map(|i| format!("s{}", i)).collect();
+        assert_eq!(Some(VString(joined)), _test_string(&format!("(do {} (+ {}))", sum, strings)));
+        assert_eq!(Some(VString(String::from("s2999s0"))),
+                   _test_string(&format!("(do {} (+ {}) (+ \"s2999\" \"s0\"))", sum, strings)));
+
+        // The same literal thousands of times over
+        let repeated = format!("(+ {})", "\"ab\" ".repeat(3000));
+        assert_eq!(Some(VString("ab".repeat(3000))), _test_string(&repeated));
+
+        let floats: String = (0..2000).map(|i| format!("{}.5 ", i)).collect();
+        assert_eq!(Some(VFloat(1999.5)), _test_string(&format!("(do (+ {}) 1999.5)", floats)));
+    }
 }
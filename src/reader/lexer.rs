@@ -1,11 +1,26 @@
+use crate::ast;
+use super::ReadError;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum TokenType {
     LeftParen,
     RightParen,
+    LeftBracket,
+    RightBracket,
+    LeftBrace,
+    RightBrace,
+    HashBrace, // #{, which opens a set
+
+    Quote,
+    Quasiquote,
+    Unquote,
+    UnquoteSplicing,
 
     Identifier(String),
+    Keyword(String),
+    Char(char),
     Number(isize),
+    Float(f64),
     Str(String),
 }
 
@@ -13,22 +28,30 @@ pub enum TokenType {
 pub struct Token {
     pub ttype: TokenType,
     pub line: usize,
+    pub column: usize,
 }
 
 pub struct Lexer {
     line: usize,
-    chars: Box<Iterator<Item=char>>,
+    column: usize,
+    start_line: usize, // Where the token being read began
+    start_column: usize,
+    chars: Box<dyn Iterator<Item=char>>,
     cur: Option<char>,
 }
 
 pub trait Tokener {
-    fn next_token(&mut self) -> Option<Token>;
+    fn next_token(&mut self) -> Result<Option<Token>, ReadError>;
 }
 
-pub fn new(src: &'static str) -> Lexer {
+pub fn new(src: &str) -> Lexer {
+    let chars: Vec<char> = src.chars().collect();
     let mut x = Lexer {
         line: 1,
-        chars: Box::from(src.chars()),
+        column: 0,
+        start_line: 1,
+        start_column: 0,
+        chars: Box::from(chars.into_iter()),
         cur: None,
     };
     x.next();
@@ -37,54 +60,145 @@ pub fn new(src: &'static str) -> Lexer {
 
 
 impl Tokener for Lexer {
-    fn next_token(&mut self) -> Option<Token> {
+    fn next_token(&mut self) -> Result<Option<Token>, ReadError> {
         if let Some(c) = self.cur_no_white() {
+            self.start_line = self.line;
+            self.start_column = self.column;
             if c == '(' {
                 self.next();
-                Some(self.make_token(TokenType::LeftParen))
+                Ok(Some(self.make_token(TokenType::LeftParen)))
             } else if c == ')' {
                 self.next();
-                Some(self.make_token(TokenType::RightParen))
-            } else if c.is_ascii_digit() {
-                self.next_number()
+                Ok(Some(self.make_token(TokenType::RightParen)))
+            } else if c == '[' {
+                self.next();
+                Ok(Some(self.make_token(TokenType::LeftBracket)))
+            } else if c == ']' {
+                self.next();
+                Ok(Some(self.make_token(TokenType::RightBracket)))
+            } else if c == '{' {
+                self.next();
+                Ok(Some(self.make_token(TokenType::LeftBrace)))
+            } else if c == '}' {
+                self.next();
+                Ok(Some(self.make_token(TokenType::RightBrace)))
+            } else if c == '\'' {
+                self.next();
+                Ok(Some(self.make_token(TokenType::Quote)))
+            } else if c == '`' {
+                self.next();
+                Ok(Some(self.make_token(TokenType::Quasiquote)))
+            } else if c == ',' {
+                if self.next() == Some('@') {
+                    self.next();
+                    Ok(Some(self.make_token(TokenType::UnquoteSplicing)))
+                } else {
+                    Ok(Some(self.make_token(TokenType::Unquote)))
+                }
             } else if c == ';' {
                 self.skip_to('\n');
                 self.next_token()
             } else if c == '"' {
                 self.next_string()
+            } else if c == '#' {
+                self.next_hash().map(Some)
             } else {
-                self.next_identifier_or_keyword()
+                self.next_identifier_or_keyword().map(Some)
             }
         } else {
-            None
+            Ok(None)
         }
     }
 
 }
 
 impl Lexer {
-    fn next_number(&mut self) -> Option<Token> {
-        let s = self.get_string_to(|c| c.is_ascii_digit());
-        if let Ok(num) = s.parse::<isize>() {
-            return Some(self.make_token(TokenType::Number(num)))
+    // Anything shaped like a number is one: 1, -1, 1.5, .5, -0.5, 1e10
+    fn parse_number(s: &str) -> Result<Option<TokenType>, &'static str> {
+        let digits = s.strip_prefix(|c| c == '-' || c == '+').unwrap_or(s);
+        if digits.bytes().all(|c| c.is_ascii_digit()) && !digits.is_empty() {
+            return s.parse::<isize>().map(|num| Some(TokenType::Number(num)))
+                .map_err(|_| "Integer literal out of range")
+        }
+        let digits = digits.strip_prefix('.').unwrap_or(digits);
+        if !digits.starts_with(|c: char| c.is_ascii_digit()) || !s.contains(['.', 'e', 'E']) {
+            return Ok(None)
+        }
+        match s.parse::<f64>() {
+            Ok(num) if num.is_finite() => Ok(Some(TokenType::Float(num))),
+            Ok(_) => Err("Float literal out of range"),
+            Err(_) => Ok(None),
         }
-        None
     }
 
-    fn next_string(&mut self) -> Option<Token> {
+    fn next_string(&mut self) -> Result<Option<Token>, ReadError> {
         self.next();
         let s = self.get_string_to(|c| c != '"');
-        let x = Some(self.make_token(TokenType::Str(s)));
+        if self.cur.is_none() {
+            return Err(self.error("unterminated string"))
+        }
+        let x = self.make_token(TokenType::Str(s));
+        self.next();
+        Ok(Some(x))
+    }
+
+    // #{ opens a set and #\ starts a character, otherwise # is just part of an identifier
+    fn next_hash(&mut self) -> Result<Token, ReadError> {
+        match self.next() {
+            Some('{') => {
+                self.next();
+                Ok(self.make_token(TokenType::HashBrace))
+            },
+            Some('\\') => self.next_char(),
+            _ => {
+                let s = self.get_string_to(Lexer::is_identifier_char);
+                Ok(self.make_token(TokenType::Identifier(format!("#{}", s))))
+            },
+        }
+    }
+
+    // #\a, #\( and #\space are all one character, as is #\x41 in hex
+    fn next_char(&mut self) -> Result<Token, ReadError> {
+        let first = match self.next() {
+            Some(c) => c,
+            None => return Err(self.error("expected a character after #\\")),
+        };
         self.next();
-        x
+        let mut s = first.to_string();
+        if Lexer::is_identifier_char(first) {
+            s.push_str(&self.get_string_to(Lexer::is_identifier_char));
+        }
+        let mut chars = s.chars();
+        if let (Some(c), None) = (chars.next(), chars.next()) {
+            return Ok(self.make_token(TokenType::Char(c)))
+        }
+        if let Some((_, c)) = ast::CHAR_NAMES.iter().find(|(name, _)| *name == s) {
+            return Ok(self.make_token(TokenType::Char(*c)))
+        }
+        let code = s.strip_prefix('x').and_then(|hex| u32::from_str_radix(hex, 16).ok());
+        match code.and_then(std::char::from_u32) {
+            Some(c) => Ok(self.make_token(TokenType::Char(c))),
+            None => Err(self.error(&format!("unknown character #\\{}", s))),
+        }
     }
 
-    fn next_identifier_or_keyword(&mut self) -> Option<Token> {
-        let s = self.get_string_to(|c| {
-            return !c.is_whitespace() &&
-                c != '(' && c != ')'
-        });
-        Some(self.make_token(TokenType::Identifier(s)))
+    fn is_identifier_char(c: char) -> bool {
+        !c.is_whitespace() &&
+            c != '(' && c != ')' &&
+            c != '[' && c != ']' &&
+            c != '{' && c != '}'
+    }
+
+    fn next_identifier_or_keyword(&mut self) -> Result<Token, ReadError> {
+        let s = self.get_string_to(Lexer::is_identifier_char);
+        if let Some(num) = Lexer::parse_number(&s).map_err(|e| self.error(e))? {
+            return Ok(self.make_token(num))
+        }
+        // A lone : is still an identifier
+        if s.len() > 1 && s.starts_with(':') {
+            return Ok(self.make_token(TokenType::Keyword(String::from(&s[1..]))))
+        }
+        Ok(self.make_token(TokenType::Identifier(s)))
     }
 
     fn get_string_to(&mut self, f: fn(char) -> bool) -> String {
@@ -97,33 +211,42 @@ impl Lexer {
                 break;
             }
         }
-        return s
+        s
     }
 
     fn make_token(&self, tok: TokenType) -> Token {
         Token {
-            line: self.line,
+            line: self.start_line,
+            column: self.start_column,
             ttype: tok,
         }
     }
 
+    fn error(&self, message: &str) -> ReadError {
+        ReadError {
+            message: String::from(message),
+            line: self.start_line,
+            column: self.start_column,
+        }
+    }
+
     fn skip_to(&mut self, to: char) {
-        loop {
-            if let Some(c) = self.cur {
-                if c != to {
-                    self.next();
-                } else {
-                    return
-                }
+        while let Some(c) = self.cur {
+            if c == to {
+                return
             }
+            self.next();
         }
     }
 
     fn next(&mut self) -> Option<char> {
-        self.cur = self.chars.next();
         if self.cur == Some('\n') {
             self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
         }
+        self.cur = self.chars.next();
         self.cur
     }
 
@@ -166,12 +289,85 @@ mod test {
         token_test(input, expected);
     }
 
+    #[test]
+    fn test_numbers() {
+        let input = "2.75 -0.5 1e10 .5 -.5 -3 - -a --1 1.5e-3 +2";
+        let expected = vec![Float(2.75), Float(-0.5), Float(1e10), Float(0.5), Float(-0.5), Number(-3),
+            Identifier(String::from("-")), Identifier(String::from("-a")), Identifier(String::from("--1")),
+            Float(1.5e-3), Number(2)];
+        token_test(input, expected);
+    }
+
+    #[test]
+    fn test_quotes() {
+        let input = "'a `(b ,c ,@d)";
+        let expected = vec![Quote, Identifier(String::from("a")), Quasiquote, LeftParen,
+            Identifier(String::from("b")), Unquote, Identifier(String::from("c")),
+            UnquoteSplicing, Identifier(String::from("d")), RightParen];
+        token_test(input, expected);
+    }
+
+    #[test]
+    fn test_keywords() {
+        let input = ":a :long-name : a:b";
+        let expected = vec![Keyword(String::from("a")), Keyword(String::from("long-name")),
+            Identifier(String::from(":")), Identifier(String::from("a:b"))];
+        token_test(input, expected);
+    }
+
+    #[test]
+    fn test_chars() {
+        let input = "#\\a #\\space #\\newline #\\x41 #\\( #\\x #\\) (#\\a)";
+        let expected = vec![Char('a'), Char(' '), Char('\n'), Char('A'), Char('('), Char('x'), Char(')'),
+            LeftParen, Char('a'), RightParen];
+        token_test(input, expected);
+
+        let mut lex = new("#\\bad");
+        assert_eq!("unknown character #\\bad", lex.next_token().expect_err("not a character").message);
+        let mut lex = new("#\\xd800");
+        assert_eq!("unknown character #\\xd800", lex.next_token().expect_err("not a character").message);
+        let mut lex = new("#\\");
+        assert_eq!("expected a character after #\\", lex.next_token().expect_err("no character").message);
+    }
+
+    #[test]
+    fn test_brackets() {
+        let input = "[1 a][]{b 2}#{#a} #";
+        let expected = vec![LeftBracket, Number(1), Identifier(String::from("a")), RightBracket,
+            LeftBracket, RightBracket, LeftBrace, Identifier(String::from("b")), Number(2), RightBrace,
+            HashBrace, Identifier(String::from("#a")), RightBrace, Identifier(String::from("#"))];
+        token_test(input, expected);
+    }
+
+    #[test]
+    fn test_positions() {
+        let mut lex = new("(a\n  bc) ; done\n\"s\"");
+        let expected = vec![(1, 1), (1, 2), (2, 3), (2, 5), (3, 1)];
+        for (line, column) in expected {
+            let tok = lex.next_token().expect("no error").expect("A token");
+            assert_eq!((line, column), (tok.line, tok.column));
+        }
+        assert!(lex.next_token().expect("no error").is_none());
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut lex = new("a\n \"abc");
+        lex.next_token().expect("no error");
+        let err = lex.next_token().expect_err("unterminated");
+        assert_eq!(ReadError { message: String::from("unterminated string"), line: 2, column: 2 }, err);
+
+        let mut lex = new("a ; comment at the end");
+        lex.next_token().expect("no error");
+        assert!(lex.next_token().expect("no error").is_none());
+    }
+
     fn token_test(input: &'static str, expected: Vec<TokenType>) {
         let mut lex = new(input);
         for x in expected {
-            assert_eq!(lex.next_token().expect("A token").ttype, x);
+            assert_eq!(lex.next_token().expect("no error").expect("A token").ttype, x);
         }
-        assert!(lex.next_token().is_none());
+        assert!(lex.next_token().expect("no error").is_none());
     }
 
 }
@@ -1,9 +1,23 @@
+use std::fmt;
 use crate::ast;
 
 mod lexer;
 mod parser;
 
-pub fn read(input: &'static str) -> Option<ast::ASTList> {
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReadError {
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+pub fn read(input: &str) -> Result<ast::ASTList, ReadError> {
     let lex = lexer::new(input);
     let mut parser = parser::new(Box::from(lex));
     parser.parse()
@@ -1,67 +1,168 @@
 use crate::ast::{ASTList, ASTAtom, Atom, List};
+use super::ReadError;
 use super::lexer::{Token, TokenType, Tokener};
 
+// Deeper nesting than this is an error rather then a stack overflow
+const MAX_DEPTH: usize = 512;
+
 pub struct Parser {
-    lexer: Box<Tokener>,
-    cur: Token,
-    next: Option<Token>,
+    lexer: Box<dyn Tokener>,
+    cur: Option<Token>,
+    // Position of the last token read, used for errors at the end of input
+    line: usize,
+    column: usize,
+    // How many forms are open around the current one
+    depth: usize,
 }
-pub fn new(mut lex: Box<Tokener>) -> Parser {
-    let cur = lex.next_token();
-    let next = lex.next_token();
+pub fn new(lex: Box<dyn Tokener>) -> Parser {
     Parser {
         lexer: lex,
-        cur: cur.expect("input should have at least one token"),
-        next,
+        cur: None,
+        line: 1,
+        column: 1,
+        depth: 0,
     }
 }
 
 impl Parser {
-    pub fn parse (&mut self) -> Option<ASTList> {
-        return Some(ASTList::new().append(self.parse_sexp()))
+    // Reads every top level form, the result is in source order
+    pub fn parse (&mut self) -> Result<ASTList, ReadError> {
+        let mut forms = ASTList::new();
+        self.next()?;
+        while self.cur.is_some() {
+            forms = forms.append(self.parse_sexp()?);
+        }
+        Ok(List::reverse(forms))
+    }
+
+    // Reads one form, leaving cur on the token after it
+    fn parse_sexp(&mut self) -> Result<ASTAtom, ReadError> {
+        if self.depth >= MAX_DEPTH {
+            return Err(self.error("Forms nested too deep"));
+        }
+        self.depth += 1;
+        let form = self.parse_form();
+        self.depth -= 1;
+        form
     }
 
-    fn parse_sexp(&mut self) -> ASTAtom {
-        if let TokenType::LeftParen = self.cur.ttype {
-            self.next();
-            return (self.parse_sexp_inner(), self.cur.line)
-        } else {
-            return (self.parse_atom(), self.cur.line)
+    fn parse_form(&mut self) -> Result<ASTAtom, ReadError> {
+        let tok = match &self.cur {
+            Some(tok) => tok,
+            None => return Err(self.error("unexpected end of input")),
+        };
+        let (line, column) = (tok.line, tok.column);
+        match tok.ttype {
+            TokenType::LeftParen => {
+                self.next()?;
+                Ok((Atom::AList(self.parse_sexp_inner("(", &TokenType::RightParen, line, column)?, line), line))
+            },
+            TokenType::LeftBracket => {
+                self.next()?;
+                Ok((Atom::AVector(self.parse_sexp_inner("[", &TokenType::RightBracket, line, column)?), line))
+            },
+            TokenType::LeftBrace => {
+                self.next()?;
+                let l = self.parse_sexp_inner("{", &TokenType::RightBrace, line, column)?;
+                if l.len() % 2 == 1 {
+                    return Err(ReadError {
+                        message: String::from("map needs a value for every key"),
+                        line,
+                        column,
+                    })
+                }
+                Ok((Atom::AMap(l), line))
+            },
+            TokenType::HashBrace => {
+                self.next()?;
+                Ok((Atom::ASet(self.parse_sexp_inner("#{", &TokenType::RightBrace, line, column)?), line))
+            },
+            TokenType::RightParen => Err(self.error("unexpected )")),
+            TokenType::RightBracket => Err(self.error("unexpected ]")),
+            TokenType::RightBrace => Err(self.error("unexpected }")),
+            TokenType::Quote => self.parse_prefixed("quote"),
+            TokenType::Quasiquote => self.parse_prefixed("quasiquote"),
+            TokenType::Unquote => self.parse_prefixed("unquote"),
+            TokenType::UnquoteSplicing => self.parse_prefixed("unquote-splicing"),
+            _ => {
+                let atom = self.parse_atom()?;
+                self.next()?;
+                Ok((atom, line))
+            }
         }
     }
 
-    fn parse_sexp_inner(&mut self) -> Atom {
+    // 'x is read as (quote x), and likewise for the other prefixes
+    fn parse_prefixed(&mut self, name: &str) -> Result<ASTAtom, ReadError> {
+        let prefix = self.error(&format!("expected a form after {}", name));
+        self.next()?;
+        match &self.cur {
+            None | Some(Token { ttype: TokenType::RightParen, .. }) |
+            Some(Token { ttype: TokenType::RightBracket, .. }) |
+            Some(Token { ttype: TokenType::RightBrace, .. }) => return Err(prefix),
+            _ => (),
+        }
+        let (inner, line) = self.parse_sexp()?;
+        let l = List::new()
+            .append(Atom::AIdentifier(String::from(name)))
+            .append(inner);
+        Ok((Atom::AList(l, line), line))
+    }
+
+    // Reads forms up to close, any other closer is an error
+    fn parse_sexp_inner(&mut self, open: &str, close: &TokenType, line: usize, column: usize) -> Result<List<Atom>, ReadError> {
         let mut l = List::new();
         loop {
-            match self.cur.ttype {
-                TokenType::RightParen => return Atom::AList(l),
-                _ => {
-                    l = l.append(self.parse_sexp().0);
-                    self.next();
-                }
+            match &self.cur {
+                None => return Err(ReadError {
+                    message: format!("unclosed {} opened at {}:{}", open, line, column),
+                    line: self.line,
+                    column: self.column,
+                }),
+                Some(Token { ttype, .. }) if ttype == close => {
+                    self.next()?;
+                    return Ok(l)
+                },
+                _ => l = l.append(self.parse_sexp()?.0),
             }
         }
     }
 
-    fn parse_atom(&mut self) -> Atom {
-        match &self.cur.ttype {
-            TokenType::Str(x) => Atom::AString(x.clone()),
-            TokenType::Number(x) => Atom::AInteger(*x),
-            TokenType::Identifier(x) => {
+    fn parse_atom(&mut self) -> Result<Atom, ReadError> {
+        Ok(match self.cur.as_ref().map(|t| &t.ttype) {
+            Some(TokenType::Str(x)) => Atom::AString(x.clone()),
+            Some(TokenType::Number(x)) => Atom::AInteger(*x),
+            Some(TokenType::Float(x)) => Atom::AFloat(*x),
+            Some(TokenType::Keyword(x)) => Atom::AKeyword(x.clone()),
+            Some(TokenType::Char(c)) => Atom::AChar(*c),
+            Some(TokenType::Identifier(x)) => {
                 match x.as_ref() {
                     "true" => Atom::ATrue,
                     "false" => Atom::AFalse,
                     _ => Atom::AIdentifier(x.clone())
                 }
             }
-            _ => panic!("Not an atom"),
+            Some(ttype) => return Err(self.error(&format!("unexpected {:?}", ttype))),
+            None => return Err(self.error("unexpected end of input")),
+        })
+    }
+
+    // An error at the current token, or the last one read if the input is over
+    fn error(&self, message: &str) -> ReadError {
+        ReadError {
+            message: String::from(message),
+            line: self.line,
+            column: self.column,
         }
     }
 
-    fn next(&mut self) {
-        if self.next.is_some() {
-            self.cur = std::mem::replace(&mut self.next, self.lexer.next_token()).expect("");
+    fn next(&mut self) -> Result<(), ReadError> {
+        self.cur = self.lexer.next_token()?;
+        if let Some(tok) = &self.cur {
+            self.line = tok.line;
+            self.column = tok.column;
         }
+        Ok(())
     }
 }
 
@@ -74,13 +175,13 @@ mod test {
         i: usize
     }
     impl Tokener for FakeLexer  {
-        fn next_token(&mut self) -> Option<Token> {
+        fn next_token(&mut self) -> Result<Option<Token>, ReadError> {
             if self.i < self.tokens.len() {
                 self.i += 1;
                 let t = &self.tokens[self.i - 1];
-                Some(Token { ttype: (*t).clone(), line: 0 })
+                Ok(Some(Token { ttype: (*t).clone(), line: 0, column: self.i }))
             } else {
-                None
+                Ok(None)
             }
         }
     }
@@ -94,7 +195,7 @@ mod test {
             .append((AList(List::new()
                 .append(AIdentifier(String::from("+")))
                 .append(AInteger(1))
-                .append(AInteger(2))
+                .append(AInteger(2)), 0
                ), 0));
         do_test(input, output);
     }
@@ -112,9 +213,77 @@ mod test {
                 .append(AList(List::new()
                     .append(AIdentifier(String::from("*")))
                     .append(AInteger(3))
-                    .append(AInteger(2))
+                    .append(AInteger(2)), 0
                    ))
+                .append(AInteger(1)), 0
+               ), 0));
+        do_test(input, output);
+    }
+
+    #[test]
+    fn test_multiple_forms() {
+        use TokenType::*;
+        use crate::ast::Atom::*;
+        let input = vec![LeftParen, Identifier(String::from("def")), Identifier(String::from("a")),
+            Number(1), RightParen, Identifier(String::from("a")), Number(2)];
+        let output = List::new()
+            .append((AInteger(2), 0))
+            .append((AIdentifier(String::from("a")), 0))
+            .append((AList(List::new()
+                .append(AIdentifier(String::from("def")))
+                .append(AIdentifier(String::from("a")))
+                .append(AInteger(1)), 0
+               ), 0));
+        do_test(input, output);
+    }
+
+    #[test]
+    fn test_quote() {
+        use TokenType::*;
+        use crate::ast::Atom::*;
+        let input = vec![Quasiquote, LeftParen, Identifier(String::from("a")),
+            Unquote, Identifier(String::from("b")), RightParen];
+        let output = List::new()
+            .append((AList(List::new()
+                .append(AIdentifier(String::from("quasiquote")))
+                .append(AList(List::new()
+                    .append(AIdentifier(String::from("a")))
+                    .append(AList(List::new()
+                        .append(AIdentifier(String::from("unquote")))
+                        .append(AIdentifier(String::from("b"))), 0
+                       )), 0
+                   )), 0
+               ), 0));
+        do_test(input, output);
+    }
+
+    #[test]
+    fn test_vector() {
+        use TokenType::*;
+        use crate::ast::Atom::*;
+        let input = vec![LeftBracket, Char('1'), LeftParen, Identifier(String::from("f")), RightParen,
+            LeftBracket, RightBracket, RightBracket];
+        let output = List::new()
+            .append((AVector(List::new()
+                .append(AChar('1'))
+                .append(AList(List::new().append(AIdentifier(String::from("f"))), 0))
+                .append(AVector(List::new()))
+               ), 0));
+        do_test(input, output);
+    }
+
+    #[test]
+    fn test_map() {
+        use TokenType::*;
+        use crate::ast::Atom::*;
+        let input = vec![LeftBrace, Keyword(String::from("a")), Number(1),
+            LeftBrace, RightBrace, HashBrace, Number(2), RightBrace, RightBrace];
+        let output = List::new()
+            .append((AMap(List::new()
+                .append(AKeyword(String::from("a")))
                 .append(AInteger(1))
+                .append(AMap(List::new()))
+                .append(ASet(List::new().append(AInteger(2))))
                ), 0));
         do_test(input, output);
     }
@@ -134,7 +303,35 @@ got:
         compare_astlist(output, ast);
     }
 
-    fn build_test(input: Vec<TokenType>) -> Option<ASTList> {
+    fn error_test(input: Vec<TokenType>, message: &str, column: usize) {
+        let err = build_test(input).expect_err("should not have parsed");
+        assert_eq!(message, err.message);
+        assert_eq!(column, err.column);
+    }
+
+    #[test]
+    fn test_errors() {
+        use TokenType::*;
+        error_test(vec![Number(1), RightParen], "unexpected )", 2);
+        error_test(vec![LeftParen, Number(1), LeftParen, Number(2), RightParen],
+                   "unclosed ( opened at 0:1", 5);
+        error_test(vec![LeftParen, Quote, RightParen], "expected a form after quote", 2);
+        error_test(vec![Number(1), Unquote], "expected a form after unquote", 2);
+        error_test(vec![LeftParen, Number(1), RightBracket], "unexpected ]", 3);
+        error_test(vec![LeftBracket, Number(1), RightParen], "unexpected )", 3);
+        error_test(vec![LeftBracket, Number(1)], "unclosed [ opened at 0:1", 2);
+        error_test(vec![LeftBrace, Number(1), RightBrace], "map needs a value for every key", 1);
+        error_test(vec![LeftBrace, RightBracket], "unexpected ]", 2);
+        error_test(vec![HashBrace, Number(1)], "unclosed #{ opened at 0:1", 2);
+    }
+
+    #[test]
+    fn test_empty() {
+        let out = build_test(vec![]).expect("should have parsed");
+        assert!(out.is_empty());
+    }
+
+    fn build_test(input: Vec<TokenType>) -> Result<ASTList, ReadError> {
         let l = FakeLexer { tokens: input, i: 0 };
         let mut p = new(Box::from(l));
         p.parse()
@@ -143,9 +340,8 @@ got:
     fn compare_ast(a: List<Atom>, b: List<Atom>) {
         assert_eq!(a.len(), b.len());
 
-        let mut ai = a.iter();
         let mut bi = b.iter();
-        while let Some(av) = ai.next() {
+        for av in a.iter() {
             if let Some(bv) = bi.next() {
                 compare_atom((*av).clone(), (*bv).clone());
             } else {
@@ -156,9 +352,8 @@ got:
     fn compare_astlist(a: ASTList, b: ASTList) {
         assert_eq!(a.len(), b.len());
 
-        let mut ai = a.iter();
         let mut bi = b.iter();
-        while let Some(av) = ai.next() {
+        for av in a.iter() {
             if let Some(bv) = bi.next() {
                 compare_atom((*av).clone().0, (*bv).clone().0);
             } else {
@@ -170,10 +365,19 @@ got:
     fn compare_atom(a: Atom, b: Atom) {
         use crate::ast::Atom::*;
         match (a, b) {
-            (AList(av), AList(bv)) => compare_ast(av, bv),
+            (AList(av, al), AList(bv, bl)) => {
+                assert_eq!(al, bl);
+                compare_ast(av, bv)
+            },
+            (AVector(av), AVector(bv)) => compare_ast(av, bv),
+            (AMap(av), AMap(bv)) => compare_ast(av, bv),
+            (ASet(av), ASet(bv)) => compare_ast(av, bv),
             (AString(av), AString(bv)) => assert_eq!(av, bv),
             (AInteger(av), AInteger(bv)) => assert_eq!(av, bv),
+            (AFloat(av), AFloat(bv)) => assert_eq!(av, bv),
             (AIdentifier(av), AIdentifier(bv)) => assert_eq!(av, bv),
+            (AKeyword(av), AKeyword(bv)) => assert_eq!(av, bv),
+            (AChar(av), AChar(bv)) => assert_eq!(av, bv),
             (ATrue, ATrue) => (),
             (AFalse, AFalse) => (),
             _ => panic!("not equal"),
@@ -1,67 +1,470 @@
+use crate::ast::List;
 use crate::chunk::Chunk;
+use crate::bytecode::{Closure, Function, Symbol, Upvalue};
 use crate::bytecode::Op;
 use crate::bytecode::Value;
 use crate::bytecode::Value::*;
 
+use std::cell::RefCell;
 use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::rc::Rc;
 
 
+#[derive(Debug)]
 pub enum VMError {
-    Runtime(String),
+    Runtime(RuntimeError),
     Compile(String),
 }
 
+// Where a runtime error happened. op, ip and line refer to the innermost
+// function, which is the first entry of the trace
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub message: String,
+    pub op: Option<Op>,
+    pub ip: usize,
+    pub line: usize,
+    pub trace: Vec<TraceFrame>,
+    pub thrown: Option<Value>, // Set when the error came from throw
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceFrame {
+    pub function: Option<String>,
+    pub line: usize,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)?;
+        if let Some(op) = self.op {
+            write!(f, " (in {:?} at ip {})", op, self.ip)?;
+        }
+        for (i, frame) in self.trace.iter().enumerate() {
+            let name = match &frame.function {
+                Some(name) => name.as_str(),
+                None if i == self.trace.len() - 1 => "<script>",
+                None => "<anonymous>",
+            };
+            let prefix = if i == 0 { "in" } else { "called from" };
+            write!(f, "\n    {} {} at line {}", prefix, name, frame.line)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for VMError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VMError::Runtime(e) => write!(f, "Runtime error: {}", e),
+            VMError::Compile(msg) => write!(f, "Compile error: {}", msg),
+        }
+    }
+}
+
+const MAX_CALL_DEPTH: usize = 65536;
+const MAX_TRACE: usize = 64; // Innermost frames kept in a runtime error
+
+// A function invocation. `base` is the stack index of its first argument,
+// and `scopes` is how many stack_frames existed before the call was made
+struct CallFrame {
+    closure: Rc<Closure>,
+    ip: usize,
+    base: usize,
+    scopes: usize,
+}
+
+// Where to resume when an error is caught, and how much state to keep
+struct Handler {
+    ip: usize,
+    call_frames: usize,
+    stack: usize,
+    stack_frames: usize,
+    unwinding: usize,
+    protect: bool,
+}
+
 pub struct VM {
     pub debug: bool,
-    pub c: Chunk,
     ip: usize,
+    op_start: usize, // ip of the op being run, as ip moves past its data
     stack: Vec<Value>,
     stack_frames: Vec<usize>,
+    call_frames: Vec<CallFrame>,
+    handlers: Vec<Handler>,
+    unwinding: Vec<RuntimeError>, // Errors waiting on an unwind-protect cleanup
+    open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    globals: HashMap<Symbol, Value>,
 }
 
 
 pub fn new(c: Chunk) -> VM {
+    let script = Function {
+        name: None,
+        arity: 0,
+        optional: 0,
+        rest: false,
+        entries: vec![0],
+        chunk: c,
+    };
     VM {
         debug: false,
-        c: c,
         ip: 0,
+        op_start: 0,
         stack: Vec::new(),
         stack_frames: Vec::new(),
+        call_frames: vec![CallFrame {
+            closure: Rc::new(Closure {
+                function: Rc::new(script),
+                upvalues: Vec::new(),
+            }),
+            ip: 0,
+            base: 0,
+            scopes: 0,
+        }],
+        handlers: Vec::new(),
+        unwinding: Vec::new(),
+        open_upvalues: Vec::new(),
+        globals: HashMap::new(),
+    }
+}
+
+// Calls f with args on a fresh VM, for running code at compile time
+pub fn apply(f: Value, args: Vec<Value>) -> Result<Value, VMError> {
+    if args.len() > 255 {
+        return err("Can't call a function with more then 255 arguments")
     }
+    let mut c = crate::chunk::new();
+    c.add_op(Op::Call, 0);
+    c.add_lit(args.len() as u8, 0);
+    c.add_op(Op::Return, 0);
+
+    let mut v = new(c);
+    v.stack.push(f);
+    v.stack.extend(args);
+    v.run()
 }
 
 impl VM {
-    fn get_data_i8(&mut self) -> i8 {
-        self.get_data() as i8
+    fn closure(&self) -> &Rc<Closure> {
+        &self.call_frames.last().expect("No call frame").closure
+    }
+
+    fn chunk(&self) -> &Chunk {
+        &self.closure().function.chunk
+    }
+
+    fn get_jump(&mut self) -> isize {
+        let amount = self.chunk().read_jump(self.ip + 1);
+        self.ip += self.chunk().jump_size(self.ip + 1);
+        amount
     }
 
     fn get_data(&mut self) -> u8 {
         self.ip += 1;
-        self.c.code[self.ip].to_lit()
+        self.chunk().code[self.ip]
+    }
+
+    fn get_data_u16(&mut self) -> u16 {
+        let data = self.chunk().read_u16(self.ip + 1);
+        self.ip += 2;
+        data
+    }
+
+    fn get_scope_start(&self, stack_back: usize) -> usize {
+        self.stack_frames[self.stack_frames.len() - 1 - stack_back]
+    }
+
+    // The function below the top argc values, if it can take them
+    fn callee(&self, argc: usize) -> Result<Rc<Closure>, VMError> {
+        if argc >= self.stack.len() {
+            return err("Empty stack")
+        }
+        let closure = match &self.stack[self.stack.len() - argc - 1] {
+            VFunction(c) => c.clone(),
+            v => return err(&format!("Value of type {} is not callable", v.type_of())),
+        };
+        let f = &closure.function;
+        if argc < f.arity || (!f.rest && argc > f.arity + f.optional) {
+            let expected = match (f.optional, f.rest) {
+                (_, true) => format!("at least {}", f.arity),
+                (0, false) => format!("{}", f.arity),
+                (optional, false) => format!("{} to {}", f.arity, f.arity + optional),
+            };
+            return err(&format!("{:?} expects {} arguments but got {}", closure, expected, argc))
+        }
+        if self.call_frames.len() >= MAX_CALL_DEPTH {
+            return err("Stack overflow")
+        }
+        Ok(closure)
+    }
+
+    // The caller's ip must already be saved in its frame
+    fn call(&mut self, closure: Rc<Closure>, argc: usize) {
+        let f = &closure.function;
+        let given = argc - f.arity;
+        let (count, entry) = if given >= f.optional {
+            let mut count = argc;
+            if f.rest {
+                // Arguments past the optional ones go in a list
+                let extra = self.stack.split_off(self.stack.len() - (given - f.optional));
+                let mut rest = List::new();
+                for v in extra.into_iter().rev() {
+                    rest = rest.append(v);
+                }
+                self.stack.push(VList(rest));
+                count = f.arity + f.optional + 1;
+            }
+            (count, f.entries[f.optional])
+        } else {
+            (argc, f.entries[given])
+        };
+        let base = self.stack.len() - count;
+        self.call_frames.push(CallFrame {
+            closure,
+            ip: 0,
+            base,
+            scopes: self.stack_frames.len(),
+        });
+        self.stack_frames.push(base);
+        self.ip = entry;
+    }
+
+    // Removes the current function's frame, keeping the callee and arguments
+    // on top of the stack, so the call returns straight to our caller
+    fn drop_frame_for_tail_call(&mut self, argc: usize) {
+        let frame = self.call_frames.pop().expect("No call frame");
+        self.close_upvalues(frame.base);
+        let start = self.stack.len() - argc - 1;
+        self.stack.drain(frame.base - 1..start);
+        self.stack_frames.truncate(frame.scopes);
+    }
+
+    // Reuses the open upvalue for a stack slot, so every closure sees the same variable
+    fn capture_upvalue(&mut self, i: usize) -> Rc<RefCell<Upvalue>> {
+        for upvalue in &self.open_upvalues {
+            if let Upvalue::Open(o) = *upvalue.borrow() {
+                if o == i {
+                    return upvalue.clone();
+                }
+            }
+        }
+        let upvalue = Rc::new(RefCell::new(Upvalue::Open(i)));
+        self.open_upvalues.push(upvalue.clone());
+        upvalue
+    }
+
+    // Moves every captured variable at or above stack index `from` off the stack
+    fn close_upvalues(&mut self, from: usize) {
+        let stack = &self.stack;
+        self.open_upvalues.retain(|upvalue| {
+            let i = match *upvalue.borrow() {
+                Upvalue::Open(i) => i,
+                Upvalue::Closed(_) => return false,
+            };
+            if i >= from {
+                upvalue.replace(Upvalue::Closed(stack[i].clone()));
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    fn pop(&mut self) -> Result<Value, VMError> {
+        match self.stack.pop() {
+            Some(v) => Ok(v),
+            None => err("Empty stack"),
+        }
+    }
+
+    fn peek(&self) -> Result<Value, VMError> {
+        match self.stack.last() {
+            Some(v) => Ok(v.clone()),
+            None => err("Empty stack"),
+        }
+    }
+
+    fn pop_stack_frame(&mut self) -> Result<usize, VMError> {
+        match self.stack_frames.pop() {
+            Some(s) => Ok(s),
+            None => err("Empty stackframes"),
+        }
+    }
+
+    fn pop_global_name(&mut self) -> Result<Symbol, VMError> {
+        match self.pop()? {
+            VSymbol(s) => Ok(s),
+            v => err(&format!("Global name must be a symbol, not {}", v.type_of())),
+        }
+    }
+
+    // Fills in where the error happened, from the state the VM stopped in.
+    // Rethrown errors keep where they first happened
+    fn locate(&self, mut e: RuntimeError) -> RuntimeError {
+        if e.op.is_some() {
+            return e
+        }
+        e.ip = self.op_start;
+        e.op = Some(Op::from_lit(self.chunk().code[e.ip]));
+        e.line = self.chunk().get_line(e.ip);
+        e.trace = self.call_frames.iter().rev().take(MAX_TRACE).enumerate().map(|(i, frame)| {
+            let ip = if i == 0 { self.op_start } else { frame.ip };
+            TraceFrame {
+                function: frame.closure.function.name.clone(),
+                line: frame.closure.function.chunk.get_line(ip),
+            }
+        }).collect();
+        e
+    }
+
+    fn make_closure(&mut self) -> Result<(), VMError> {
+        let count = self.get_data();
+        let function = match self.pop()? {
+            VFunction(c) => c.function.clone(),
+            _ => return err("Closure needs a function"),
+        };
+        let mut upvalues = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            // 0 is an upvalue of this closure, 1 a local, and 2 a local with 2 byte operands
+            let kind = self.get_data();
+            let (index, stack_back) = if kind == 2 {
+                (self.get_data_u16() as usize, self.get_data_u16() as usize)
+            } else {
+                (self.get_data() as usize, self.get_data() as usize)
+            };
+            if kind == 0 {
+                upvalues.push(self.closure().upvalues[index].clone());
+            } else {
+                let i = index + self.get_scope_start(stack_back);
+                upvalues.push(self.capture_upvalue(i));
+            }
+        }
+        self.stack.push(VFunction(Rc::new(Closure { function, upvalues })));
+        Ok(())
+    }
+
+    pub fn run(&mut self) -> Result<Value, VMError> {
+        loop {
+            match self.execute() {
+                Err(VMError::Runtime(e)) => {
+                    let e = self.locate(e);
+                    if let Err(e) = self.catch(e) {
+                        return Err(VMError::Runtime(e))
+                    }
+                },
+                res => return res,
+            }
+        }
+    }
+
+    // Unwinds to the innermost handler. A try gets the error as a value, while
+    // an unwind-protect holds on to it until its cleanup rethrows it
+    fn catch(&mut self, e: RuntimeError) -> Result<(), RuntimeError> {
+        let handler = match self.handlers.pop() {
+            Some(h) => h,
+            None => return Err(e),
+        };
+        // Errors from inside a cleanup replace the one it was cleaning up after
+        self.unwinding.truncate(handler.unwinding);
+        self.call_frames.truncate(handler.call_frames);
+        self.close_upvalues(handler.stack);
+        self.stack.truncate(handler.stack);
+        self.stack_frames.truncate(handler.stack_frames);
+        if handler.protect {
+            self.unwinding.push(e);
+        } else {
+            let value = match e.thrown {
+                Some(v) => v,
+                None => VError(e.message),
+            };
+            self.stack_frames.push(self.stack.len());
+            self.stack.push(value);
+        }
+        self.ip = handler.ip;
+        Ok(())
     }
-    pub fn run(&mut self) -> Result<Value, VMError>  {
+
+    fn execute(&mut self) -> Result<Value, VMError> {
         loop {
-            let op = self.c.code[self.ip];
+            self.op_start = self.ip;
+            let op = Op::from_lit(self.chunk().code[self.ip]);
             if self.debug {
-                crate::chunk::disassemble_instruction(&self.c, self.ip);
+                crate::chunk::disassemble_instruction(self.chunk(), self.ip);
             }
             match op {
                 Op::Return => {
-                    let v = self.stack.pop().expect("Empty Stack");
-                    return Ok(v)
+                    let v = self.pop()?;
+                    let frame = self.call_frames.pop().expect("No call frame");
+                    if self.call_frames.is_empty() {
+                        return Ok(v)
+                    }
+                    self.close_upvalues(frame.base);
+                    self.stack.truncate(frame.base - 1);
+                    self.stack_frames.truncate(frame.scopes);
+                    self.stack.push(v);
+                    self.ip = self.call_frames.last().expect("No call frame").ip;
+                },
+                Op::Call => {
+                    let argc = self.get_data() as usize;
+                    let closure = self.callee(argc)?;
+                    self.call_frames.last_mut().expect("No call frame").ip = self.ip;
+                    self.call(closure, argc);
+                    continue;
+                },
+                Op::TailCall => {
+                    let argc = self.get_data() as usize;
+                    let closure = self.callee(argc)?;
+                    if self.call_frames.len() > 1 {
+                        self.drop_frame_for_tail_call(argc);
+                    } else {
+                        self.call_frames.last_mut().expect("No call frame").ip = self.ip;
+                    }
+                    self.call(closure, argc);
+                    continue;
+                },
+                Op::Closure => self.make_closure()?,
+                Op::GetUpvalue => {
+                    let i = self.get_data();
+                    let upvalue = self.closure().upvalues[i as usize].clone();
+                    let v = match &*upvalue.borrow() {
+                        Upvalue::Open(o) => self.stack[*o].clone(),
+                        Upvalue::Closed(v) => v.clone(),
+                    };
+                    self.stack.push(v);
+                },
+                Op::SetUpvalue => {
+                    let i = self.get_data();
+                    let v = self.pop()?;
+                    let upvalue = self.closure().upvalues[i as usize].clone();
+                    let open = match &mut *upvalue.borrow_mut() {
+                        Upvalue::Open(o) => Some(*o),
+                        Upvalue::Closed(c) => {
+                            *c = v.clone();
+                            None
+                        },
+                    };
+                    if let Some(o) = open {
+                        self.stack[o] = v.clone();
+                    }
+                    self.stack.push(v);
                 },
                 Op::Discard1 => {
-                    self.stack.pop().expect("Empty stack");
+                    self.pop()?;
                 },
                 Op::Discard => {
                     let amount = self.get_data();
                     for _ in 0..amount {
-                        self.stack.pop().expect("Empty stack");
+                        self.pop()?;
                     }
                 },
 
                 Op::Const1 | Op::Const2 | Op::Const3 => {
-                    self.stack.push(self.c.read_constant(self.ip));
+                    let v = self.chunk().read_constant(self.ip);
+                    self.stack.push(v);
                     self.ip += match op {
                         Op::Const1 => 1,
                         Op::Const2 => 2,
@@ -71,20 +474,26 @@ impl VM {
                 },
 
                 Op::Negate => {
-                    let val = self.stack.pop().expect("Empty stack");
+                    let val = self.pop()?;
                     match val {
                         VFloat(f) => self.stack.push(VFloat(-f)),
-                        VInt(f) => self.stack.push(VInt(-f)),
+                        VInt(f) => match f.checked_neg() {
+                            Some(v) => self.stack.push(VInt(v)),
+                            None => return err("integer overflow in -"),
+                        },
                         _ => return err("Value is not negatable")
                     }
                 },
 
                 Op::Car | Op::Cdr => {
-                    let val = self.stack.pop().expect("Empty stack");
+                    let val = self.pop()?;
                     match val {
                         VList(l) => {
                             match op {
-                                Op::Car => self.stack.push(l.head().expect("List needs head").clone()),
+                                Op::Car => match l.head() {
+                                    Some(v) => self.stack.push(v.clone()),
+                                    None => return err("Can't take the car of an empty list"),
+                                },
                                 Op::Cdr => self.stack.push(VList(l.tail())),
                                 _ => panic!(""),
                             }
@@ -94,18 +503,257 @@ impl VM {
                 },
 
                 Op::Cons => {
-                    let elem = self.stack.pop().expect("Empty stack");
-                    let list = self.stack.pop();
-                    if let Some(VList(l)) = list {
+                    let elem = self.pop()?;
+                    let list = self.pop()?;
+                    if let VList(l) = list {
                         self.stack.push(VList(l.append(elem)))
                     } else {
                         return err("not a cons-able object")
                     }
                 },
 
+                Op::Splice => {
+                    let prefix = self.pop()?;
+                    let rest = self.pop()?;
+                    if let (VList(prefix), VList(mut l)) = (prefix, rest) {
+                        let elems: Vec<&Value> = prefix.iter().collect();
+                        for elem in elems.into_iter().rev() {
+                            l = l.append(elem.clone());
+                        }
+                        self.stack.push(VList(l))
+                    } else {
+                        return err("unquote-splicing needs a list")
+                    }
+                },
+
+                Op::Nth => {
+                    let coll = self.pop()?;
+                    let i = index(&self.pop()?, "nth")?;
+                    let v = match &coll {
+                        VVector(v) => v.get(i),
+                        VList(l) => l.iter().nth(i),
+                        v => return err(&format!("nth needs a Vector or List, not {}", v.type_of())),
+                    };
+                    match v {
+                        Some(v) => self.stack.push(v.clone()),
+                        None => return err(&format!("Index {} is out of bounds for a {} of {}",
+                                                    i, coll.type_of(), count(&coll).unwrap_or(0))),
+                    }
+                },
+                Op::Assoc => {
+                    let val = self.pop()?;
+                    let key = self.pop()?;
+                    match self.pop()? {
+                        VVector(v) => {
+                            let i = index(&key, "assoc")?;
+                            match v.set(i, val) {
+                                Some(v) => self.stack.push(VVector(v)),
+                                None => return err(&format!("Index {} is out of bounds for a Vector of {}", i, v.len())),
+                            }
+                        },
+                        VMap(m) => self.stack.push(VMap(m.insert(key, val))),
+                        v => return err(&format!("assoc needs a Vector or Map, not {}", v.type_of())),
+                    }
+                },
+                Op::Dissoc => {
+                    let key = self.pop()?;
+                    match self.pop()? {
+                        VMap(m) => self.stack.push(VMap(m.remove(&key))),
+                        v => return err(&format!("dissoc needs a Map, not {}", v.type_of())),
+                    }
+                },
+                Op::Get => {
+                    let coll = self.pop()?;
+                    let key = self.pop()?;
+                    let default = self.pop()?;
+                    let v = match &coll {
+                        VMap(m) => m.get(&key),
+                        VVector(v) => match key {
+                            VInt(i) if i >= 0 => v.get(i as usize),
+                            _ => None,
+                        },
+                        v => return err(&format!("get needs a Map or Vector, not {}", v.type_of())),
+                    };
+                    self.stack.push(v.cloned().unwrap_or(default));
+                },
+                Op::Contains => {
+                    let coll = self.pop()?;
+                    let key = self.pop()?;
+                    let found = match &coll {
+                        VMap(m) => m.contains_key(&key),
+                        VSet(s) => s.contains(&key),
+                        VVector(v) => matches!(key, VInt(i) if i >= 0 && (i as usize) < v.len()),
+                        v => return err(&format!("contains? needs a Map, Set or Vector, not {}", v.type_of())),
+                    };
+                    self.stack.push(VBool(found));
+                },
+                Op::Keys | Op::Vals => {
+                    let m = match self.pop()? {
+                        VMap(m) => m,
+                        v => return err(&format!("{} needs a Map, not {}",
+                                                 if op == Op::Keys { "keys" } else { "vals" }, v.type_of())),
+                    };
+                    let items: Vec<&Value> = if op == Op::Keys { m.keys().collect() } else { m.values().collect() };
+                    let mut l = List::new();
+                    for v in items.into_iter().rev() {
+                        l = l.append(v.clone());
+                    }
+                    self.stack.push(VList(l));
+                },
+                Op::Merge => {
+                    let from = self.pop()?;
+                    match (self.pop()?, from) {
+                        (VMap(into), VMap(from)) => {
+                            let merged = from.iter().fold(into, |m, (k, v)| m.insert(k.clone(), v.clone()));
+                            self.stack.push(VMap(merged));
+                        },
+                        (into, from) => {
+                            let bad = if let VMap(_) = into { from } else { into };
+                            return err(&format!("merge needs Maps, not {}", bad.type_of()))
+                        },
+                    }
+                },
+                Op::Conj => {
+                    let elem = self.pop()?;
+                    match self.pop()? {
+                        VVector(v) => self.stack.push(VVector(v.push(elem))),
+                        VList(l) => self.stack.push(VList(l.append(elem))),
+                        VSet(s) => self.stack.push(VSet(s.insert(elem))),
+                        v => return err(&format!("conj needs a Vector, List or Set, not {}", v.type_of())),
+                    }
+                },
+                Op::Count => {
+                    let coll = self.pop()?;
+                    match count(&coll) {
+                        Some(n) => self.stack.push(VInt(n as isize)),
+                        None => return err(&format!("Can't count a {}", coll.type_of())),
+                    }
+                },
+                Op::Subvec => {
+                    let v = match self.pop()? {
+                        VVector(v) => v,
+                        v => return err(&format!("subvec needs a Vector, not {}", v.type_of())),
+                    };
+                    let start = index(&self.pop()?, "subvec")?;
+                    let end = match self.pop()? {
+                        VBool(false) => v.len(),
+                        end => index(&end, "subvec")?,
+                    };
+                    match v.slice(start, end) {
+                        Some(v) => self.stack.push(VVector(v)),
+                        None => return err(&format!("Can't take {} to {} of a Vector of {}", start, end, v.len())),
+                    }
+                },
+
+                Op::Union => {
+                    let from = self.pop()?;
+                    let into = match self.pop()? {
+                        VSet(s) => s,
+                        v => return err(&format!("union needs a Set, not {}", v.type_of())),
+                    };
+                    let s = match from {
+                        VSet(s) => into.union(&s),
+                        VList(l) => l.iter().fold(into, |s, v| s.insert(v.clone())),
+                        VVector(v) => v.iter().fold(into, |s, v| s.insert(v.clone())),
+                        v => return err(&format!("union needs a collection, not {}", v.type_of())),
+                    };
+                    self.stack.push(VSet(s));
+                },
+                Op::Intersection | Op::Difference | Op::IsSubset => {
+                    let (a, b) = if op == Op::IsSubset {
+                        let a = self.pop()?;
+                        (a, self.pop()?)
+                    } else {
+                        let b = self.pop()?;
+                        (self.pop()?, b)
+                    };
+                    match (a, b) {
+                        (VSet(a), VSet(b)) => self.stack.push(match op {
+                            Op::Intersection => VSet(a.intersection(&b)),
+                            Op::Difference => VSet(a.difference(&b)),
+                            _ => VBool(a.is_subset(&b)),
+                        }),
+                        (a, b) => {
+                            let name = match op {
+                                Op::Intersection => "intersection",
+                                Op::Difference => "difference",
+                                _ => "subset?",
+                            };
+                            let bad = if let VSet(_) = a { b } else { a };
+                            return err(&format!("{} needs Sets, not {}", name, bad.type_of()))
+                        },
+                    }
+                },
+
+                Op::IsSymbol => {
+                    let val = self.pop()?;
+                    self.stack.push(VBool(matches!(val, VSymbol(_))));
+                },
+                Op::IsKeyword => {
+                    let val = self.pop()?;
+                    self.stack.push(VBool(matches!(val, VKeyword(_))));
+                },
+                Op::CharToInteger => {
+                    match self.pop()? {
+                        VChar(c) => self.stack.push(VInt(c as isize)),
+                        v => return err(&format!("char->integer needs a Char, not {}", v.type_of())),
+                    }
+                },
+                Op::IntegerToChar => {
+                    let i = match self.pop()? {
+                        VInt(i) => i,
+                        v => return err(&format!("integer->char needs an Int, not {}", v.type_of())),
+                    };
+                    match u32::try_from(i).ok().and_then(std::char::from_u32) {
+                        Some(c) => self.stack.push(VChar(c)),
+                        None => return err(&format!("No character has the code {}", i)),
+                    }
+                },
+                Op::StringRef => {
+                    let s = match self.pop()? {
+                        VString(s) => s,
+                        v => return err(&format!("string-ref needs a String, not {}", v.type_of())),
+                    };
+                    let i = index(&self.pop()?, "string-ref")?;
+                    match s.chars().nth(i) {
+                        Some(c) => self.stack.push(VChar(c)),
+                        None => return err(&format!("Index {} is out of bounds for a String of {}", i, s.chars().count())),
+                    }
+                },
+                Op::CharUpcase | Op::IsAlphabetic => {
+                    let c = match self.pop()? {
+                        VChar(c) => c,
+                        v => return err(&format!("{} needs a Char, not {}",
+                                                 if op == Op::CharUpcase { "char-upcase" } else { "char-alphabetic?" },
+                                                 v.type_of())),
+                    };
+                    if op == Op::CharUpcase {
+                        // Only when it stays one character, so not for the likes of ß
+                        let mut upper = c.to_uppercase();
+                        match (upper.next(), upper.next()) {
+                            (Some(u), None) => self.stack.push(VChar(u)),
+                            _ => self.stack.push(VChar(c)),
+                        }
+                    } else {
+                        self.stack.push(VBool(c.is_alphabetic()));
+                    }
+                },
+                Op::SymbolToString => {
+                    match self.pop()? {
+                        VSymbol(s) => self.stack.push(VString(String::from(s.as_str()))),
+                        v => return err(&format!("symbol->string needs a Symbol, not {}", v.type_of())),
+                    }
+                },
+                Op::StringToSymbol => {
+                    match self.pop()? {
+                        VString(s) => self.stack.push(VSymbol(Symbol::intern(&s))),
+                        v => return err(&format!("string->symbol needs a String, not {}", v.type_of())),
+                    }
+                },
+
                 Op::Add | Op::Subtract | Op::Multiply | Op::Divide => {
-                    let v1 = self.stack.pop().expect("Empty stack");
-                    let v2 = self.stack.pop().expect("Empty stack");
+                    let v1 = self.pop()?;
+                    let v2 = self.pop()?;
                     let v_ans = binary_operator(op, v1, v2);
                     match v_ans {
                         Ok(v) => self.stack.push(v),
@@ -118,7 +766,7 @@ impl VM {
 
                     let mut params = Vec::with_capacity(count as usize);
                     for _ in 0..count {
-                        params.push(self.stack.pop().expect("Empty stack"));
+                        params.push(self.pop()?);
                     }
                     let v_ans = comparison_operator(op, params);
                     match v_ans {
@@ -126,11 +774,11 @@ impl VM {
                         Err(e) => return Err(e)
                     }
                 },
-                Op::Jump | Op::JumpTrue => {
-                    let amount = self.get_data_i8();
+                Op::Jump | Op::JumpTrue | Op::JumpWide | Op::JumpTrueWide => {
+                    let amount = self.get_jump();
                     if match op {
-                        Op::Jump => true,
-                        Op::JumpTrue => self.stack.pop().expect("Empty stack").is_truthy(),
+                        Op::Jump | Op::JumpWide => true,
+                        Op::JumpTrue | Op::JumpTrueWide => self.pop()?.is_truthy(),
                         _ => unimplemented!(),
                     } {
                         self.ip = ((self.ip as i64) + (amount as i64)) as usize;
@@ -138,35 +786,114 @@ impl VM {
                 },
 
                 Op::Store => {
-                    let value = self.stack.pop().expect("Empty Stack");
+                    let value = self.pop()?;
                     self.stack.push(value.clone());
                     self.stack.push(value);
                 },
-                Op::Load => {
-                    let loc = self.get_data();
-                    let stack_back = self.get_data();
-                    self.stack.push(self.stack[(loc as usize) + self.stack_frames[stack_back as usize]].clone());
+                Op::Load | Op::Set | Op::LoadWide | Op::SetWide => {
+                    let (loc, stack_back) = match op {
+                        Op::Load | Op::Set => (self.get_data() as usize, self.get_data() as usize),
+                        _ => (self.get_data_u16() as usize, self.get_data_u16() as usize),
+                    };
+                    let i = loc + self.get_scope_start(stack_back);
+                    if let Op::Load | Op::LoadWide = op {
+                        self.stack.push(self.stack[i].clone());
+                    } else {
+                        self.stack[i] = self.pop()?;
+                        self.stack.push(self.stack[i].clone());
+                    }
+                },
+
+                Op::DefGlobal => {
+                    let name = self.pop_global_name()?;
+                    let value = self.peek()?;
+                    self.globals.insert(name, value);
+                },
+                Op::GetGlobal => {
+                    let name = self.pop_global_name()?;
+                    match self.globals.get(&name) {
+                        Some(v) => {
+                            let v = v.clone();
+                            self.stack.push(v)
+                        },
+                        None => return err(&format!("Var not found: [{}]", name.as_str())),
+                    }
                 },
-                Op::Set => {
-                    let loc = self.get_data();
-                    let stack_back = self.get_data();
-                    self.stack[(loc as usize) + self.stack_frames[stack_back as usize]] = self.stack.pop().expect("Empty Stack");
-                    self.stack.push(self.stack[(loc as usize) + self.stack_frames[stack_back as usize]].clone());
+                Op::SetGlobal => {
+                    let name = self.pop_global_name()?;
+                    let value = self.peek()?;
+                    match self.globals.get_mut(&name) {
+                        Some(v) => *v = value,
+                        None => return err(&format!("Can't set undefined var [{}]", name.as_str())),
+                    }
                 },
 
                 Op::CreateFrame => {
                     self.stack_frames.push(self.stack.len());
                 },
                 Op::DropFrame => {
-                    let s = self.stack_frames.pop().expect("Empty stackframes");
+                    let s = self.pop_stack_frame()?;
+                    self.close_upvalues(s);
                     self.stack.truncate(s);
                 },
                 Op::DropFrameSaveReturn => {
-                    let v = self.stack.pop().expect("Empty stack");
-                    let s = self.stack_frames.pop().expect("Empty stackframes");
+                    let v = self.pop()?;
+                    let s = self.pop_stack_frame()?;
+                    self.close_upvalues(s);
                     self.stack.truncate(s);
                     self.stack.push(v);
                 },
+                Op::CloseUpvalues => {
+                    let back = self.get_data_u16() as usize;
+                    let from = self.get_scope_start(back);
+                    self.close_upvalues(from);
+                },
+
+                Op::Try | Op::Protect | Op::TryWide | Op::ProtectWide => {
+                    let amount = self.get_jump();
+                    self.handlers.push(Handler {
+                        ip: ((self.ip as i64) + (amount as i64) + 1) as usize,
+                        call_frames: self.call_frames.len(),
+                        stack: self.stack.len(),
+                        stack_frames: self.stack_frames.len(),
+                        unwinding: self.unwinding.len(),
+                        protect: matches!(op, Op::Protect | Op::ProtectWide),
+                    });
+                },
+                Op::EndTry => {
+                    self.handlers.pop();
+                },
+                Op::Rethrow => {
+                    match self.unwinding.pop() {
+                        Some(e) => return Err(VMError::Runtime(e)),
+                        None => return err("Nothing to rethrow"),
+                    }
+                },
+                Op::Throw => {
+                    let v = self.pop()?;
+                    let message = match &v {
+                        VError(msg) => msg.clone(),
+                        v => format!("Uncaught throw of {}", v),
+                    };
+                    return Err(VMError::Runtime(RuntimeError {
+                        message,
+                        op: None,
+                        ip: 0,
+                        line: 0,
+                        trace: Vec::new(),
+                        thrown: Some(v),
+                    }))
+                },
+                Op::IsError => {
+                    let val = self.pop()?;
+                    self.stack.push(VBool(matches!(val, VError(_))));
+                },
+                Op::ErrorMessage => {
+                    match self.pop()? {
+                        VError(msg) => self.stack.push(VString(msg)),
+                        v => return err(&format!("error-message needs an Error, not {}", v.type_of())),
+                    }
+                },
 
                 _ => return err("Unimplemented op")
             }
@@ -175,14 +902,38 @@ impl VM {
     }
 }
 
+// Indexes have to be Ints that aren't negative
+fn index(v: &Value, name: &str) -> Result<usize, VMError> {
+    match v {
+        VInt(i) if *i >= 0 => Ok(*i as usize),
+        VInt(i) => err(&format!("{} needs an index of 0 or more, not {}", name, i)),
+        v => err(&format!("{} needs an Int index, not {}", name, v.type_of())),
+    }
+}
+
+fn count(v: &Value) -> Option<usize> {
+    match v {
+        VVector(v) => Some(v.len()),
+        VMap(m) => Some(m.len()),
+        VSet(s) => Some(s.len()),
+        VList(l) => Some(l.len()),
+        VString(s) => Some(s.chars().count()),
+        _ => None,
+    }
+}
+
 fn comparison_operator(op: Op, vals: Vec<Value>) -> Result<Value, VMError> {
     let mut iter = vals.iter().rev();
-    let cur_opt = iter.next();
-    if cur_opt.is_some() {
-        let mut cur = cur_opt.expect("");
+    if let Some(mut cur) = iter.next() {
         let mut val = Value::VBool(true);
-        while let Some(next) = iter.next() {
-            let cmp_val = next.partial_cmp(cur);
+        for next in iter {
+            // Equality is the same as for map keys, so it works for values without an ordering
+            let cmp_val = match op {
+                // NaN only equals itself as a map key
+                Op::Equal if next == cur && !matches!(next, VFloat(f) if f.is_nan()) => Some(Ordering::Equal),
+                Op::Equal => Some(Ordering::Less),
+                _ => next.partial_cmp(cur),
+            };
             if let Some(cmp) = cmp_val {
                 val = match op {
                     Op::Equal => Value::VBool(cmp == Ordering::Equal),
@@ -217,8 +968,13 @@ fn comparison_operator(op: Op, vals: Vec<Value>) -> Result<Value, VMError> {
 }
 
 fn binary_operator(op: Op, v1: Value, v2: Value) -> Result<Value, VMError> {
+    if let (Op::Divide, VInt(0)) = (op, &v2) {
+        return err("Division by zero")
+    }
     let s1 = v1.type_of();
     let s2 = v2.type_of();
+    // Integer math only fails by overflowing, as division by zero was ruled out
+    let ints = matches!((&v1, &v2), (VInt(_), VInt(_)));
     let x = match op {
         Op::Add => v1 + v2,
         Op::Subtract => v1 - v2,
@@ -226,6 +982,15 @@ fn binary_operator(op: Op, v1: Value, v2: Value) -> Result<Value, VMError> {
         Op::Divide => v1 / v2,
         _ => return err("binary operator not found for type float")
     };
+    if let (Value::VErr, true) = (&x, ints) {
+        let name = match op {
+            Op::Add => "+",
+            Op::Subtract => "-",
+            Op::Multiply => "*",
+            _ => "/",
+        };
+        return err(&format!("integer overflow in {}", name))
+    }
     if let Value::VErr = x {
         return err(format!("operator {:?} is not usable with types {} and {}",
                            op, s1, s2).as_ref())
@@ -233,6 +998,14 @@ fn binary_operator(op: Op, v1: Value, v2: Value) -> Result<Value, VMError> {
     Ok(x)
 }
 
+// The location is filled in by VM::run once the error reaches it
 fn err<T>(msg: &str) -> Result<T, VMError> {
-    Err(VMError::Runtime(String::from(msg)))
+    Err(VMError::Runtime(RuntimeError {
+        message: String::from(msg),
+        op: None,
+        ip: 0,
+        line: 0,
+        trace: Vec::new(),
+        thrown: None,
+    }))
 }